@@ -0,0 +1,118 @@
+/*
+ * Copyright 2023 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+
+use mintmark::{render, RenderOptions};
+
+/// A printer that discards output and never reports status
+struct Sink;
+
+impl Read for Sink {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const PARAGRAPH: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, \
+    sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.  Ut \
+    enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut \
+    aliquip ex ea commodo consequat.";
+
+/// Long runs of plain paragraphs
+fn text_heavy() -> String {
+    let mut doc = String::new();
+    for i in 0..200 {
+        writeln!(doc, "{}\n", PARAGRAPH).unwrap();
+        if i % 20 == 0 {
+            writeln!(doc, "## Section {}\n", i / 20).unwrap();
+        }
+    }
+    doc
+}
+
+/// Dithered grayscale images and barcodes
+fn image_heavy() -> String {
+    // plain PGM gradient, as wide as the printer allows
+    let (width, height) = (200, 64);
+    let mut pgm = format!("P2 {} {} 255\n", width, height);
+    for y in 0..height {
+        for x in 0..width {
+            write!(pgm, "{} ", (x + y) * 255 / (width + height)).unwrap();
+        }
+        pgm.push('\n');
+    }
+    let mut doc = String::new();
+    for i in 0..10 {
+        writeln!(doc, "```image\n{}```\n", pgm).unwrap();
+        writeln!(doc, "```image levels=3\n{}```\n", pgm).unwrap();
+        writeln!(doc, "```qrcode\nhttps://example.com/{}\n```\n", i).unwrap();
+        writeln!(doc, "```code128\nSKU-{:06}\n```\n", i).unwrap();
+    }
+    doc
+}
+
+/// Frequent format changes, which exercise every spooling pass
+fn formatting_heavy() -> String {
+    let mut doc = String::new();
+    for i in 0..100 {
+        writeln!(
+            doc,
+            "Some **bold**, *emphasized*, ~~struck~~, and `red` text, \
+             with **bold `red` and ~~struck *emphasis*~~** mixed in.\n"
+        )
+        .unwrap();
+        writeln!(
+            doc,
+            "- item {}\n  - nested ~~item~~\n> quoted **text**\n",
+            i
+        )
+        .unwrap();
+        writeln!(doc, "```text shade strikethrough\nshaded {}\n```\n", i).unwrap();
+    }
+    doc
+}
+
+fn bench_render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render");
+    let options = RenderOptions::default();
+    for (name, doc) in [
+        ("text", text_heavy()),
+        ("image", image_heavy()),
+        ("formatting", formatting_heavy()),
+    ] {
+        group.throughput(Throughput::Bytes(doc.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| render(&doc, &options, &mut Sink).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_render);
+criterion_main!(benches);
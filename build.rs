@@ -27,10 +27,44 @@ fn main() -> Result<()> {
 }
 
 fn custom_chars() -> Result<()> {
+    let (mut out, regular_count) = load_chars("src/custom", false)?;
+    // Italic glyphs are enabled only for emphasized runs, so they can't
+    // share the printer's single user-defined character set with
+    // always-on custom characters.  Pad them to the full cell width so
+    // they line up with the other print passes.
+    let (italic, italic_count) = load_chars("src/custom/italic", true)?;
+    if regular_count > 0 && italic_count > 0 {
+        bail!("Italic custom characters can't be combined with regular ones");
+    }
+    let count = regular_count + italic_count;
+
+    // enable custom fonts if we have custom chars
+    if !out.is_empty() {
+        out.extend(b"\x1b%\x01");
+    }
+    out.extend(&italic);
+
+    // write output
+    if count > MAX_CHARS {
+        bail!("too many custom characters: {count} > {MAX_CHARS}");
+    }
+    write(
+        format!("{}/custom.rs", env::var("OUT_DIR")?),
+        format!(
+            "const CUSTOM_CHAR_INIT: [u8; {}] = {:?};\nconst HAVE_ITALIC: bool = {};\n",
+            out.len(),
+            out,
+            italic_count > 0
+        ),
+    )?;
+    Ok(())
+}
+
+fn load_chars(dir: &str, fixed_width: bool) -> Result<(Vec<u8>, u32)> {
     let mut out: Vec<u8> = Vec::new();
     let mut count = 0;
     for (font_name, font_num, max_width) in [("wide", 0, 12), ("narrow", 1, 10)] {
-        let dir_path = format!("src/custom/{font_name}");
+        let dir_path = format!("{dir}/{font_name}");
         println!("cargo:rerun-if-changed={}", dir_path);
 
         let dir_iter = match read_dir(dir_path) {
@@ -90,6 +124,7 @@ fn custom_chars() -> Result<()> {
                     ent.path().display()
                 );
             }
+            let w = if fixed_width { max_width } else { w };
 
             // serialize character
             buf.extend(b"\x1b&\x02");
@@ -135,19 +170,5 @@ fn custom_chars() -> Result<()> {
             out.append(&mut buf);
         }
     }
-
-    // enable custom fonts if we have custom chars
-    if !out.is_empty() {
-        out.extend(b"\x1b%\x01");
-    }
-
-    // write output
-    if count > MAX_CHARS {
-        bail!("too many custom characters: {count} > {MAX_CHARS}");
-    }
-    write(
-        format!("{}/custom.rs", env::var("OUT_DIR")?),
-        format!("const CUSTOM_CHAR_INIT: [u8; {}] = {:?};\n", out.len(), out),
-    )?;
-    Ok(())
+    Ok((out, count))
 }
@@ -0,0 +1,39 @@
+/*
+ * Copyright 2023 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mintmark::{render, RenderOptions};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    // use the first byte to pick options
+    let Some((flags, input)) = data.split_first() else {
+        return;
+    };
+    let Ok(input) = std::str::from_utf8(input) else {
+        return;
+    };
+    let options = RenderOptions {
+        rtl: flags & 0x01 != 0,
+        collapse_hard_breaks: flags & 0x02 != 0,
+        ..Default::default()
+    };
+    let mut output = Cursor::new(Vec::new());
+    // errors are fine; panics aren't
+    let _ = render(input, &options, &mut output);
+});
@@ -14,24 +14,36 @@
  * limitations under the License.
 */
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use barcoders::sym::code128::Code128;
 use base64::Engine;
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, LumaA, Pixel, Rgb, RgbImage};
 use qrcode::{EcLevel, QrCode};
 use std::borrow::Cow;
 use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
 
-use crate::render::{Format, FormatFlags, Renderer};
+use crate::font::{glyph, glyph_pixel, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::render::{Format, FormatFlags, Renderer, BEEP_DEFAULT_TIME, LINE_PIXELS_IMAGE};
 use crate::strike::{Strike, StrikeColors, StrikeImage};
+use crate::table::{TableOptions, TableOverflow};
 
 #[derive(Debug, Eq, PartialEq)]
 pub(crate) enum CodeBlockConfig {
+    Beep(BeepBlock),
+    BitImage(BitImageBlock),
     Bitmap(BitmapBlock),
     Code128(Code128Block),
     Image(ImageBlock),
+    Menu(MenuBlock),
     QrCode(QrCodeBlock),
+    QrGrid(QrGridBlock),
+    Table(TableBlock),
     Text(TextBlock),
+    Title(TitleBlock),
 }
 
 impl CodeBlockConfig {
@@ -40,14 +52,32 @@ impl CodeBlockConfig {
         let language = it.next().unwrap_or("");
         let options = it.collect::<Vec<&str>>();
         use CodeBlockConfig::*;
-        Ok(match language {
-            "bitmap" => Bitmap(BitmapBlock::from_options(&options)?),
-            "code128" => Code128(Code128Block::from_options(&options)?),
-            "image" => Image(ImageBlock::from_options(&options)?),
-            "qrcode" => QrCode(QrCodeBlock::from_options(&options)?),
-            "text" => Text(TextBlock::from_options(&options)?),
-            _ => Text(TextBlock::default()),
-        })
+        let config = match language {
+            "beep" => BeepBlock::from_options(&options).map(Beep),
+            "bitimage" => BitImageBlock::from_options(&options).map(BitImage),
+            "bitmap" => BitmapBlock::from_options(&options).map(Bitmap),
+            "code128" => Code128Block::from_options(&options).map(Code128),
+            "image" => ImageBlock::from_options(&options).map(Image),
+            "menu" => MenuBlock::from_options(&options).map(Menu),
+            "qrcode" => QrCodeBlock::from_options(&options).map(QrCode),
+            "qrgrid" => QrGridBlock::from_options(&options).map(QrGrid),
+            "table" => TableBlock::from_options(&options).map(Table),
+            "text" => TextBlock::from_options(&options).map(Text),
+            "title" => TitleBlock::from_options(&options).map(Title),
+            // Languages we don't handle, such as those for syntax
+            // highlighting, are printed as plain code blocks
+            _ => return Ok(Text(TextBlock::default())),
+        };
+        config.with_context(|| format!("invalid options for {} code block", language))
+    }
+
+    // Resolve files named by the block inside image_dir, the only place
+    // input is allowed to load files from
+    pub(crate) fn resolve_paths(&mut self, image_dir: Option<&Path>) -> Result<()> {
+        match self {
+            CodeBlockConfig::Image(block) => block.resolve_paths(image_dir),
+            _ => Ok(()),
+        }
     }
 
     pub(crate) fn render(
@@ -57,33 +87,87 @@ impl CodeBlockConfig {
     ) -> Result<()> {
         use CodeBlockConfig::*;
         match self {
+            Beep(block) => block.render(renderer, contents),
+            BitImage(block) => block.render(renderer, contents),
             Bitmap(block) => block.render(renderer, contents),
             Code128(block) => block.render(renderer, contents),
             Image(block) => block.render(renderer, contents),
+            Menu(block) => block.render(renderer, contents),
             QrCode(block) => block.render(renderer, contents),
+            QrGrid(block) => block.render(renderer, contents),
+            // The contents are Markdown, so the caller renders them
+            Table(_) => unreachable!("table block contents rendered directly"),
             Text(block) => block.render(renderer, contents),
+            Title(block) => block.render(renderer, contents),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct BeepBlock {
+    count: u8,
+    on: u8,
+    off: u8,
+}
+
+impl Default for BeepBlock {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            on: BEEP_DEFAULT_TIME,
+            off: BEEP_DEFAULT_TIME,
         }
     }
 }
 
+impl BeepBlock {
+    const OPTIONS: &[&str] = &["count=N", "off=N", "on=N"];
+
+    fn from_options(options: &[&str]) -> Result<Self> {
+        let mut block = Self::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("count", value)) => block.count = parse_option_value(option, value)?,
+                Some(("on", value)) => block.on = parse_option_value(option, value)?,
+                Some(("off", value)) => block.off = parse_option_value(option, value)?,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
+            }
+        }
+        Ok(block)
+    }
+
+    fn render(&self, renderer: &mut Renderer<impl Read + Write>, _contents: &str) -> Result<()> {
+        renderer.beep(self.count, self.on, self.off)
+    }
+}
+
 #[derive(Debug, Default, Eq, PartialEq)]
 pub(crate) struct BitmapBlock {
     bold: bool,
+    trim: bool,
 }
 
 impl BitmapBlock {
+    const OPTIONS: &[&str] = &["bold", "trim"];
+
     fn from_options(options: &[&str]) -> Result<Self> {
         let mut block = Self::default();
         for option in options {
             match *option {
                 "bold" => block.bold = true,
-                _ => bail!("unknown option '{}'", option),
+                "trim" => block.trim = true,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
             }
         }
         Ok(block)
     }
 
     fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
+        renderer.write_image(&self.image(contents)?)
+    }
+
+    fn image(&self, contents: &str) -> Result<StrikeImage> {
+        let contents = trim_maybe(contents, self.trim);
         let contents = contents.trim_end_matches('\n');
         let width = contents.split('\n').fold(0, |acc, l| acc.max(l.len()));
         let height = contents.split('\n').count();
@@ -109,83 +193,441 @@ impl BitmapBlock {
                 );
             }
         }
-        renderer.write_image(&image)
+        Ok(image)
+    }
+}
+
+/// Pre-rasterized 1-bit image data: the width in dots on the first line,
+/// then one line per row, packed MSB first and padded to a whole byte.
+/// Whitespace within a row is ignored.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct BitImageBlock {
+    base64: bool,
+    bold: bool,
+}
+
+impl BitImageBlock {
+    const OPTIONS: &[&str] = &["base64", "bold"];
+
+    fn from_options(options: &[&str]) -> Result<Self> {
+        let mut block = Self::default();
+        for option in options {
+            match *option {
+                "base64" => block.base64 = true,
+                "bold" => block.bold = true,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
+            }
+        }
+        Ok(block)
+    }
+
+    fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
+        renderer.write_image(&self.image(contents)?)
+    }
+
+    fn image(&self, contents: &str) -> Result<StrikeImage> {
+        let mut lines = contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let width: u32 = lines
+            .next()
+            .context("missing bit image width")?
+            .parse()
+            .context("invalid bit image width")?;
+        if width == 0 || width as usize > LINE_PIXELS_IMAGE {
+            bail!(
+                "bit image width {} outside range 1-{}",
+                width,
+                LINE_PIXELS_IMAGE
+            );
+        }
+        let row_bytes = (width as usize + 7) / 8;
+        let rows = lines
+            .enumerate()
+            .map(|(y, line)| {
+                let line = line.split_whitespace().collect::<String>();
+                let row = if self.base64 {
+                    base64::engine::general_purpose::STANDARD
+                        .decode(&line)
+                        .context("decoding base64")
+                } else {
+                    decode_hex(&line)
+                }
+                .with_context(|| format!("bit image row {}", y + 1))?;
+                if row.len() != row_bytes {
+                    bail!(
+                        "bit image row {} has {} bytes; expected {}",
+                        y + 1,
+                        row.len(),
+                        row_bytes
+                    );
+                }
+                Ok(row)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let on = Strike([if self.bold { 2 } else { 1 }, 0]);
+        let height = rows.len().try_into().context("invalid bit image height")?;
+        Ok(StrikeImage::from_fn(width, height, |x, y| {
+            if rows[y as usize][x as usize / 8] & (0x80 >> (x % 8)) != 0 {
+                on
+            } else {
+                Strike([0, 0])
+            }
+        }))
     }
 }
 
 #[derive(Debug, Default, Eq, PartialEq)]
 pub(crate) struct Code128Block {
     bold: bool,
+    labeled: bool,
 }
 
 impl Code128Block {
+    const OPTIONS: &[&str] = &["bold", "labeled"];
+
+    // Indent of the label and value text, which group with the barcode
+    const LABEL_INDENT: usize = 2;
+
     fn from_options(options: &[&str]) -> Result<Self> {
         let mut block = Self::default();
         for option in options {
             match *option {
                 "bold" => block.bold = true,
-                _ => bail!("unknown option '{}'", option),
+                "labeled" => block.labeled = true,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
             }
         }
         Ok(block)
     }
 
     fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
-        // Build code, character set B
-        let data = Code128::new(format!("\u{0181}{}", contents.trim()))
-            .context("creating barcode")?
-            .encode();
-        // The barcoders image feature pulls in all default features of `image`,
-        // which are large.  Handle the conversion ourselves.
-        let mut image =
-            StrikeImage::new(data.len().try_into().context("barcode size overflow")?, 24);
-        for (x, value) in data.iter().enumerate() {
-            for y in 0..image.height() {
-                image.put_pixel(
-                    x.try_into().context("invalid X coordinate")?,
-                    y,
-                    if *value > 0 {
-                        if self.bold {
-                            Strike([2, 0])
-                        } else {
-                            Strike([1, 0])
-                        }
+        if !self.labeled {
+            let image = code128_image(contents.trim(), self.bold, renderer.line_pixels_image())?;
+            return renderer.write_image(&image);
+        }
+
+        // The first line is a label, printed above the barcode and wrapped
+        // like ordinary text.  The value is repeated below the barcode in
+        // human-readable form.
+        let (label, value) = contents
+            .trim()
+            .split_once('\n')
+            .context("labeled barcode needs a label line and a value line")?;
+        let value = value.trim();
+        let image = code128_image(value, self.bold, renderer.line_pixels_image())?;
+        renderer.set_format(renderer.format().with_added_indent(Self::LABEL_INDENT));
+        let result = renderer.write(&format!("{}\n", label.trim()));
+        renderer.restore_format();
+        result?;
+        renderer.write_image(&image)?;
+        renderer.set_format(renderer.format().with_added_indent(Self::LABEL_INDENT));
+        let result = renderer.write(&format!("{}\n", value));
+        renderer.restore_format();
+        result
+    }
+}
+
+/// Build a Code 128 barcode image, 24 dots tall, failing if it would be
+/// wider than max_width.
+pub(crate) fn code128_image(value: &str, bold: bool, max_width: usize) -> Result<StrikeImage> {
+    // Each character is 11 modules, plus 11 each for the start and check
+    // characters and 13 for the stop character.  Every module is one dot.
+    let width = |len: usize| 11 * len + 35;
+    if width(value.chars().count()) > max_width {
+        bail!(
+            "content too long for paper at this scale; max {} characters",
+            max_width.saturating_sub(width(0)) / 11
+        );
+    }
+    // Build code, character set B
+    let data = Code128::new(format!("\u{0181}{}", value))
+        .context("creating barcode")?
+        .encode();
+    // The barcoders image feature pulls in all default features of `image`,
+    // which are large.  Handle the conversion ourselves.
+    let mut image = StrikeImage::new(data.len().try_into().context("barcode size overflow")?, 24);
+    for (x, value) in data.iter().enumerate() {
+        for y in 0..image.height() {
+            image.put_pixel(
+                x.try_into().context("invalid X coordinate")?,
+                y,
+                if *value > 0 {
+                    if bold {
+                        Strike([2, 0])
                     } else {
-                        Strike([0, 0])
-                    },
-                );
-            }
+                        Strike([1, 0])
+                    }
+                } else {
+                    Strike([0, 0])
+                },
+            );
         }
-        renderer.write_image(&image)
     }
+    Ok(image)
 }
 
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq)]
 pub(crate) struct ImageBlock {
     base64: bool,
     bicolor: bool,
+    black: Option<PathBuf>,
+    red: Option<PathBuf>,
+    levels: u8,
+    correct_aspect: bool,
+    min_width: u32,
+    auto_invert: Option<u8>,
+    fit: bool,
+}
+
+impl Default for ImageBlock {
+    fn default() -> Self {
+        Self {
+            base64: false,
+            bicolor: false,
+            black: None,
+            red: None,
+            levels: 1,
+            correct_aspect: false,
+            min_width: 0,
+            auto_invert: None,
+            fit: false,
+        }
+    }
 }
 
 impl ImageBlock {
+    const OPTIONS: &[&str] = &[
+        "autoinvert",
+        "autoinvert=N",
+        "base64",
+        "bicolor",
+        "black=PATH",
+        "correctaspect",
+        "fit",
+        "levels=N",
+        "minwidth=N",
+        "red=PATH",
+    ];
+
+    // Each extra level adds a strike pass over the darkest areas, which
+    // is slow and eventually tears the paper
+    const MAX_LEVELS: u8 = 4;
+
+    // Invert when the mean luminance is below this percentage
+    const AUTO_INVERT_DEFAULT: u8 = 50;
+
     fn from_options(options: &[&str]) -> Result<Self> {
         let mut block = ImageBlock::default();
         for option in options {
-            match *option {
-                "base64" => block.base64 = true,
-                "bicolor" => block.bicolor = true,
-                _ => bail!("unknown option '{}'", option),
+            match option.split_once('=') {
+                Some(("black", path)) => block.black = Some(path.into()),
+                Some(("red", path)) => block.red = Some(path.into()),
+                Some(("levels", value)) => block.levels = parse_option_value(option, value)?,
+                Some(("minwidth", value)) => block.min_width = parse_option_value(option, value)?,
+                Some(("autoinvert", value)) => {
+                    block.auto_invert = Some(parse_option_value(option, value)?)
+                }
+                None if *option == "autoinvert" => {
+                    block.auto_invert = Some(Self::AUTO_INVERT_DEFAULT)
+                }
+                None if *option == "base64" => block.base64 = true,
+                None if *option == "bicolor" => block.bicolor = true,
+                None if *option == "correctaspect" => block.correct_aspect = true,
+                None if *option == "fit" => block.fit = true,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
+            }
+        }
+        if (block.black.is_some() || block.red.is_some()) && (block.base64 || block.bicolor) {
+            bail!("black and red layers can't be combined with base64 or bicolor");
+        }
+        if !(1..=Self::MAX_LEVELS).contains(&block.levels) {
+            bail!(
+                "levels {} outside range 1-{}",
+                block.levels,
+                Self::MAX_LEVELS
+            );
+        }
+        if (block.black.is_some() || block.red.is_some()) && block.levels != 1 {
+            bail!("black and red layers can't be combined with levels");
+        }
+        if let Some(threshold) = block.auto_invert {
+            if threshold > 100 {
+                bail!("autoinvert threshold {} outside range 0-100", threshold);
+            }
+            if block.black.is_some() || block.red.is_some() {
+                bail!("black and red layers can't be combined with autoinvert");
             }
         }
         Ok(block)
     }
 
+    fn resolve_paths(&mut self, image_dir: Option<&Path>) -> Result<()> {
+        for path in [&mut self.black, &mut self.red].into_iter().flatten() {
+            *path = resolve_layer_path(image_dir, path)?;
+        }
+        Ok(())
+    }
+
     fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
+        let max_width = renderer.line_pixels_image() as u32;
+        let image = self.image(contents, max_width)?;
+        renderer.write_image(&upscale(image, self.min_width, max_width))
+    }
+
+    fn image(&self, contents: &str, max_width: u32) -> Result<StrikeImage> {
+        if self.black.is_some() || self.red.is_some() {
+            if !contents.trim().is_empty() {
+                bail!("image block with black or red layer files must be empty");
+            }
+            return self.separated_image(max_width);
+        }
         let data = base64_maybe_decode(contents, self.base64)?;
-        let image = image::load_from_memory(&data)?.into_rgb8();
-        renderer.write_image(&StrikeColors::new(self.bicolor).map_image(&image))
+        let mut image = image::load_from_memory(&data)?.into_rgb8();
+        if self.correct_aspect {
+            image = correct_aspect(&image);
+        }
+        // Scale before dithering, so the dither pattern is at the
+        // printed resolution
+        if self.fit {
+            image = fit_width(image, max_width);
+        }
+        if let Some(threshold) = self.auto_invert {
+            if mean_luminance(&image) < threshold as u64 * 255 / 100 {
+                imageops::invert(&mut image);
+            }
+        }
+        Ok(StrikeColors::new(self.bicolor, self.levels).map_image(&image))
+    }
+
+    // Combine pre-separated black and red layers without dithering.  Each
+    // layer is thresholded: dark, opaque pixels are printed.
+    fn separated_image(&self, max_width: u32) -> Result<StrikeImage> {
+        let layers = [&self.black, &self.red]
+            .iter()
+            .map(|path| {
+                path.as_ref()
+                    .map(|path| -> Result<_> {
+                        let layer = image::open(path)
+                            .with_context(|| format!("loading {}", path.display()))?
+                            .into_luma_alpha8();
+                        let layer = if self.correct_aspect {
+                            correct_aspect(&layer)
+                        } else {
+                            layer
+                        };
+                        Ok(if self.fit {
+                            fit_width(layer, max_width)
+                        } else {
+                            layer
+                        })
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut dimensions = layers.iter().flatten().map(|layer| layer.dimensions());
+        let (width, height) = dimensions.next().expect("no image layers");
+        if dimensions.any(|d| d != (width, height)) {
+            bail!("black and red layers have different dimensions");
+        }
+        let mut image = StrikeImage::new(width, height);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            for (channel, layer) in layers.iter().enumerate() {
+                if let Some(layer) = layer {
+                    let LumaA([luma, alpha]) = *layer.get_pixel(x, y);
+                    pixel.0[channel] = (luma < 128 && alpha >= 128) as u8;
+                }
+            }
+        }
+        Ok(image)
     }
 }
 
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct MenuBlock {}
+
+impl MenuBlock {
+    const OPTIONS: &[&str] = &[];
+
+    fn from_options(options: &[&str]) -> Result<Self> {
+        if let Some(option) = options.first() {
+            return Err(unknown_option(option, Self::OPTIONS));
+        }
+        Ok(Self::default())
+    }
+
+    fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
+        let columns = renderer.line_columns();
+        let mut text = String::new();
+        for line in contents.lines() {
+            if line.trim().is_empty() {
+                text.push('\n');
+                continue;
+            }
+            let (name, price) = line
+                .rsplit_once('|')
+                .with_context(|| format!("menu line missing '|': {}", line))?;
+            text.push_str(&menu_line(name.trim(), price.trim(), columns));
+        }
+        renderer.write(&text)
+    }
+}
+
+// Lay out a menu item as "name ...... price", with the price against
+// the right edge.  Names too long to leave room for a leader are
+// wrapped.
+fn menu_line(name: &str, price: &str, columns: usize) -> String {
+    // minimum leader: space, two dots, space
+    const LEADER: usize = 4;
+    let len = |s: &str| s.chars().count();
+    let columns = columns.max(1);
+
+    // wrap the name, breaking overlong words
+    let mut lines: Vec<String> = vec![String::new()];
+    for word in name.split_whitespace() {
+        let mut word = word.to_string();
+        loop {
+            let last = lines.last_mut().unwrap();
+            let space = !last.is_empty() as usize;
+            if len(last) + space + len(&word) <= columns {
+                if space > 0 {
+                    last.push(' ');
+                }
+                last.push_str(&word);
+                break;
+            }
+            if last.is_empty() {
+                // word longer than the line
+                let split = word
+                    .char_indices()
+                    .nth(columns)
+                    .map(|(i, _)| i)
+                    .unwrap_or(word.len());
+                let rest = word.split_off(split);
+                last.push_str(&word);
+                word = rest;
+            }
+            lines.push(String::new());
+        }
+    }
+    // move the price to its own line if it won't fit after the name
+    let last = lines.last().unwrap();
+    if !last.is_empty() && len(last) + LEADER + len(price) > columns {
+        lines.push(String::new());
+    }
+
+    let mut last = lines.pop().unwrap();
+    let mut ret: String = lines.into_iter().map(|line| line + "\n").collect();
+    if !last.is_empty() {
+        last.push(' ');
+    }
+    let dots = columns.saturating_sub(len(&last) + len(price) + 1);
+    ret.push_str(&last);
+    ret.push_str(&".".repeat(dots));
+    ret.push(' ');
+    ret.push_str(price);
+    ret.push('\n');
+    ret
+}
+
 #[derive(Debug, Default, Eq, PartialEq)]
 pub(crate) struct QrCodeBlock {
     base64: bool,
@@ -193,81 +635,289 @@ pub(crate) struct QrCodeBlock {
 }
 
 impl QrCodeBlock {
+    const OPTIONS: &[&str] = &["base64", "bold"];
+
     fn from_options(options: &[&str]) -> Result<Self> {
         let mut block = Self::default();
         for option in options {
             match *option {
                 "base64" => block.base64 = true,
                 "bold" => block.bold = true,
-                _ => bail!("unknown option '{}'", option),
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
             }
         }
         Ok(block)
     }
 
     fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
-        // Build code
         let data = base64_maybe_decode(contents.trim(), self.base64)?;
-        let code =
-            QrCode::with_error_correction_level(data, EcLevel::L).context("creating QR code")?;
-        // qrcode is supposed to be able to generate an Image directly,
-        // but that doesn't work.  Take the long way around.
-        // https://github.com/kennytm/qrcode-rust/issues/19
-        let image_str_with_newlines = code
-            .render()
-            .module_dimensions(2, 2)
-            .dark_color('#')
-            .light_color(' ')
-            .build();
-        let image_str = image_str_with_newlines.replace('\n', "");
-        let height = image_str_with_newlines.len() - image_str.len() + 1;
-        let width = image_str.len() / height;
-        let mut image = StrikeImage::new(
-            width.try_into().context("invalid QR code width")?,
-            height.try_into().context("invalid QR code height")?,
-        );
-        for (item, pixel) in image_str.chars().zip(image.pixels_mut()) {
-            *pixel = if item == '#' {
-                if self.bold {
-                    Strike([2, 0])
-                } else {
-                    Strike([1, 0])
+        renderer.write_image(&qrcode_image(
+            &data,
+            self.bold,
+            renderer.line_pixels_image(),
+        )?)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct QrGridBlock {
+    bold: bool,
+    columns: u32,
+}
+
+impl Default for QrGridBlock {
+    fn default() -> Self {
+        Self {
+            bold: false,
+            columns: 2,
+        }
+    }
+}
+
+impl QrGridBlock {
+    const OPTIONS: &[&str] = &["bold", "columns=N"];
+
+    fn from_options(options: &[&str]) -> Result<Self> {
+        let mut block = Self::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("columns", value)) => block.columns = parse_option_value(option, value)?,
+                None if *option == "bold" => block.bold = true,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
+            }
+        }
+        if block.columns == 0 {
+            bail!("columns must be at least 1");
+        }
+        Ok(block)
+    }
+
+    // Each line is a payload, optionally followed by '|' and a caption.
+    // Codes are centered in equal-width cells spanning the line, and
+    // captions are centered in text columns of the same width below them.
+    fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
+        let cell_width = renderer.line_pixels_image() / self.columns as usize;
+        let caption_width = renderer.line_columns() / self.columns as usize;
+        let items: Vec<(&str, &str)> = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| match line.rsplit_once('|') {
+                Some((payload, caption)) => (payload.trim(), caption.trim()),
+                None => (line.trim(), ""),
+            })
+            .collect();
+
+        for row in items.chunks(self.columns as usize) {
+            let codes = row
+                .iter()
+                .map(|(payload, _)| {
+                    qrcode_image(payload.as_bytes(), self.bold, cell_width).with_context(|| {
+                        format!(
+                            "QR code for '{}' doesn't fit in a {}-dot grid cell; use fewer columns",
+                            payload, cell_width
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let cell_width = cell_width as u32;
+            let height = codes.iter().map(|code| code.height()).max().unwrap_or(0);
+            let mut image = StrikeImage::new(cell_width * self.columns, height);
+            for (i, code) in codes.iter().enumerate() {
+                let left = i as u32 * cell_width + (cell_width - code.width()) / 2;
+                for (x, y, pixel) in code.enumerate_pixels() {
+                    image.put_pixel(left + x, y, *pixel);
                 }
+            }
+            renderer.write_image(&image)?;
+
+            if row.iter().any(|(_, caption)| !caption.is_empty()) {
+                let mut line = String::new();
+                for (_, caption) in row {
+                    let caption: String = caption.chars().take(caption_width).collect();
+                    let len = caption.chars().count();
+                    let left = (caption_width - len) / 2;
+                    line.push_str(&" ".repeat(left));
+                    line.push_str(&caption);
+                    line.push_str(&" ".repeat(caption_width - len - left));
+                }
+                renderer.write(&format!("{}\n", line.trim_end()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build a QR code image with two dots per module, failing if it would be
+/// wider than max_width.
+pub(crate) fn qrcode_image(data: &[u8], bold: bool, max_width: usize) -> Result<StrikeImage> {
+    // Build code
+    let code = match qrcode_fit(data, max_width) {
+        Some(code) => code,
+        None => bail!(
+            "content too long for paper at this scale; max {} bytes",
+            qrcode_capacity(data, max_width)
+        ),
+    };
+    // qrcode is supposed to be able to generate an Image directly,
+    // but that doesn't work.  Take the long way around.
+    // https://github.com/kennytm/qrcode-rust/issues/19
+    let image_str_with_newlines = code
+        .render()
+        .module_dimensions(2, 2)
+        .dark_color('#')
+        .light_color(' ')
+        .build();
+    let image_str = image_str_with_newlines.replace('\n', "");
+    let height = image_str_with_newlines.len() - image_str.len() + 1;
+    let width = image_str.len() / height;
+    let mut image = StrikeImage::new(
+        width.try_into().context("invalid QR code width")?,
+        height.try_into().context("invalid QR code height")?,
+    );
+    for (item, pixel) in image_str.chars().zip(image.pixels_mut()) {
+        *pixel = if item == '#' {
+            if bold {
+                Strike([2, 0])
             } else {
-                Strike([0, 0])
-            };
+                Strike([1, 0])
+            }
+        } else {
+            Strike([0, 0])
+        };
+    }
+
+    Ok(image)
+}
+
+// Encode data as a QR code, if the result fits in max_width.  The
+// rendered code has a 4-module quiet zone on each side.
+fn qrcode_fit(data: &[u8], max_width: usize) -> Option<QrCode> {
+    QrCode::with_error_correction_level(data, EcLevel::L)
+        .ok()
+        .filter(|code| (code.width() + 8) * 2 <= max_width)
+}
+
+// The length of the longest prefix of data whose QR code fits in
+// max_width.  Capacity depends on the content as well as the size:
+// digits and uppercase letters pack more densely than other bytes.
+fn qrcode_capacity(data: &[u8], max_width: usize) -> usize {
+    // The empty prefix is assumed to fit and the full data not to
+    let (mut fits, mut too_long) = (0, data.len());
+    while too_long - fits > 1 {
+        let len = (fits + too_long) / 2;
+        if qrcode_fit(&data[..len], max_width).is_some() {
+            fits = len;
+        } else {
+            too_long = len;
         }
+    }
+    fits
+}
 
-        renderer.write_image(&image)
+// A Markdown table, with options for how it's laid out
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct TableBlock {
+    pub(crate) options: TableOptions,
+}
+
+impl TableBlock {
+    const OPTIONS: &[&str] = &[
+        "minwidth=N",
+        "overflow=flow",
+        "overflow=list",
+        "shade",
+        "widths=N,*",
+    ];
+
+    fn from_options(options: &[&str]) -> Result<Self> {
+        let mut block = Self::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("minwidth", value)) => {
+                    block.options.min_width = parse_option_value(option, value)?
+                }
+                Some(("overflow", "flow")) => block.options.overflow = TableOverflow::Flow,
+                Some(("overflow", "list")) => block.options.overflow = TableOverflow::List,
+                None if *option == "shade" => block.options.shade = true,
+                Some(("widths", value)) => {
+                    block.options.widths = Some(
+                        value
+                            .split(',')
+                            .map(|width| match width {
+                                "*" => Ok(None),
+                                _ => match parse_option_value(option, width)? {
+                                    0 => bail!("table widths must be at least 1"),
+                                    width => Ok(Some(width)),
+                                },
+                            })
+                            .collect::<Result<_>>()?,
+                    )
+                }
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
+            }
+        }
+        if block.options.min_width == 0 {
+            bail!("minwidth must be at least 1");
+        }
+        Ok(block)
     }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub(crate) struct TextBlock {
     format: Rc<Format>,
+    trim: bool,
 }
 
 impl Default for TextBlock {
     fn default() -> Self {
         Self {
             format: Format::new().with_red(true),
+            trim: false,
         }
     }
 }
 
 impl TextBlock {
+    const OPTIONS: &[&str] = &[
+        "black",
+        "bold",
+        "doubleheight",
+        "doublewidth",
+        "italic",
+        "shade",
+        "size=N",
+        "strikethrough",
+        "trim",
+        "underline",
+        "wide",
+    ];
+
     fn from_options(options: &[&str]) -> Result<Self> {
         let mut block = Self::default();
         for option in options {
+            if *option == "trim" {
+                block.trim = true;
+                continue;
+            }
+            if let Some(("size", value)) = option.split_once('=') {
+                block.format = block
+                    .format
+                    .without_flags(FormatFlags::DOUBLE_HEIGHT | FormatFlags::DOUBLE_WIDTH)
+                    .with_flags(text_size_flags(parse_option_value(option, value)?)?);
+                continue;
+            }
             block.format = match *option {
                 "black" => block.format.with_red(false),
                 "bold" => block.format.with_flags(FormatFlags::EMPHASIZED),
                 "doubleheight" => block.format.with_flags(FormatFlags::DOUBLE_HEIGHT),
                 "doublewidth" => block.format.with_flags(FormatFlags::DOUBLE_WIDTH),
+                "italic" => block.format.with_italic(),
+                "shade" => block.format.with_shade(true),
                 "strikethrough" => block.format.with_strikethrough(true),
                 "underline" => block.format.with_flags(FormatFlags::UNDERLINE),
                 "wide" => block.format.without_flags(FormatFlags::NARROW),
-                _ => bail!("unknown option '{}'", option),
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
             }
         }
         Ok(block)
@@ -275,13 +925,269 @@ impl TextBlock {
 
     fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
         renderer.set_format(self.format.clone());
-        let result = renderer.write(contents);
+        let result = renderer.write(&trim_maybe(contents, self.trim));
         renderer.restore_format();
         result
     }
 }
 
-fn base64_maybe_decode(contents: &str, base64: bool) -> Result<Cow<[u8]>> {
+/// Text rasterized with the built-in 5x7 font and enlarged beyond the
+/// printer's own character sizes.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct TitleBlock {
+    bold: bool,
+    red: bool,
+    scale: u32,
+}
+
+impl Default for TitleBlock {
+    fn default() -> Self {
+        Self {
+            bold: false,
+            red: false,
+            scale: 3,
+        }
+    }
+}
+
+impl TitleBlock {
+    const OPTIONS: &[&str] = &["bold", "red", "scale=N"];
+
+    // At this scale a line holds four characters
+    const MAX_SCALE: u32 = 8;
+
+    fn from_options(options: &[&str]) -> Result<Self> {
+        let mut block = Self::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("scale", value)) => block.scale = parse_option_value(option, value)?,
+                None if *option == "bold" => block.bold = true,
+                None if *option == "red" => block.red = true,
+                _ => return Err(unknown_option(option, Self::OPTIONS)),
+            }
+        }
+        if !(1..=Self::MAX_SCALE).contains(&block.scale) {
+            bail!("scale {} outside range 1-{}", block.scale, Self::MAX_SCALE);
+        }
+        Ok(block)
+    }
+
+    fn render(&self, renderer: &mut Renderer<impl Read + Write>, contents: &str) -> Result<()> {
+        let image = self.image(contents, renderer.line_pixels_image() as u32)?;
+        renderer.write_image(&image)
+    }
+
+    fn image(&self, contents: &str, max_width: u32) -> Result<StrikeImage> {
+        // one blank column between characters, and two blank rows
+        // between lines
+        let cell_width = GLYPH_WIDTH + 1;
+        let line_height = GLYPH_HEIGHT + 2;
+        let lines: Vec<Vec<char>> = contents
+            .trim_end_matches('\n')
+            .split('\n')
+            .map(|line| line.trim().chars().collect())
+            .collect();
+        let columns = lines.iter().map(|line| line.len()).max().unwrap_or(0) as u32;
+        let max_columns = (max_width / self.scale + 1) / cell_width;
+        if columns > max_columns {
+            bail!(
+                "title too wide for paper at scale {}; max {} characters per line",
+                self.scale,
+                max_columns
+            );
+        }
+        let width = (columns * cell_width).saturating_sub(1);
+        let height = (lines.len() as u32 * line_height).saturating_sub(2);
+        let on = match (self.red, self.bold) {
+            (false, false) => Strike([1, 0]),
+            (false, true) => Strike([2, 0]),
+            (true, false) => Strike([0, 1]),
+            (true, true) => Strike([0, 2]),
+        };
+        let mut image = StrikeImage::new(width * self.scale, height * self.scale);
+        for (row, line) in lines.iter().enumerate() {
+            // center each line
+            let line_width = (line.len() as u32 * cell_width).saturating_sub(1);
+            let left = (width - line_width) / 2;
+            let top = row as u32 * line_height;
+            for (column, ch) in line.iter().enumerate() {
+                let glyph = glyph(*ch);
+                let x0 = left + column as u32 * cell_width;
+                for x in 0..GLYPH_WIDTH {
+                    for y in 0..GLYPH_HEIGHT {
+                        if !glyph_pixel(glyph, x, y) {
+                            continue;
+                        }
+                        for dx in 0..self.scale {
+                            for dy in 0..self.scale {
+                                image.put_pixel(
+                                    (x0 + x) * self.scale + dx,
+                                    (top + y) * self.scale + dy,
+                                    on,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn unknown_option(option: &str, valid: &[&str]) -> anyhow::Error {
+    if valid.is_empty() {
+        anyhow!("unknown option '{}'; this block takes no options", option)
+    } else {
+        anyhow!(
+            "unknown option '{}'; valid options: {}",
+            option,
+            valid.join(", ")
+        )
+    }
+}
+
+// The printer can only double the height and/or width of characters, so
+// there are four sizes in increasing order of area.
+fn text_size_flags(size: u8) -> Result<FormatFlags> {
+    Ok(match size {
+        1 => FormatFlags::empty(),
+        2 => FormatFlags::DOUBLE_HEIGHT,
+        3 => FormatFlags::DOUBLE_WIDTH,
+        4 => FormatFlags::DOUBLE_HEIGHT | FormatFlags::DOUBLE_WIDTH,
+        _ => bail!("size {} outside range 1-4", size),
+    })
+}
+
+// Bit images print at 80 dpi horizontally but only 72 dpi vertically,
+// so they come out stretched vertically.  Resample the height to
+// compensate.
+fn correct_aspect<P>(image: &ImageBuffer<P, Vec<u8>>) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let height = ((image.height() * 72 + 40) / 80).max(1);
+    imageops::resize(image, image.width(), height, FilterType::Triangle)
+}
+
+// Find a layer file relative to the image directory.  Absolute paths,
+// "..", and symlinks leading out of the directory are refused, and
+// without a directory no files can be loaded at all.
+fn resolve_layer_path(image_dir: Option<&Path>, path: &Path) -> Result<PathBuf> {
+    let image_dir = match image_dir {
+        Some(dir) => dir,
+        None => bail!("loading image layer files isn't enabled"),
+    };
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!(
+            "image layer path {} must be relative, without '..'",
+            path.display()
+        );
+    }
+    let image_dir = image_dir
+        .canonicalize()
+        .with_context(|| format!("finding image directory {}", image_dir.display()))?;
+    let resolved = image_dir
+        .join(path)
+        .canonicalize()
+        .with_context(|| format!("loading {}", path.display()))?;
+    if !resolved.starts_with(&image_dir) {
+        bail!(
+            "image layer path {} leads outside the image directory",
+            path.display()
+        );
+    }
+    Ok(resolved)
+}
+
+// Shrink the image to at most max_width dots wide, preserving its
+// aspect ratio.  Narrower images are returned unchanged, as is
+// everything if max_width is 0, leaving write_image() to reject it.
+fn fit_width<P>(image: ImageBuffer<P, Vec<u8>>, max_width: u32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let (width, height) = image.dimensions();
+    if width <= max_width || max_width == 0 {
+        return image;
+    }
+    let height = ((height as u64 * max_width as u64 + width as u64 / 2) / width as u64).max(1);
+    imageops::resize(&image, max_width, height as u32, FilterType::Triangle)
+}
+
+// Mean Rec. 601 luma of the image, 0-255.
+fn mean_luminance(image: &RgbImage) -> u64 {
+    let pixels = image.width() as u64 * image.height() as u64;
+    if pixels == 0 {
+        return 255;
+    }
+    let sum: u64 = image
+        .pixels()
+        .map(|Rgb([r, g, b])| 299 * *r as u64 + 587 * *g as u64 + 114 * *b as u64)
+        .sum();
+    sum / 1000 / pixels
+}
+
+// Enlarge the image by the smallest integer factor that makes it at
+// least min_width dots wide, without exceeding max_width.  Each dot
+// becomes a square block, so edges stay sharp.
+fn upscale(image: StrikeImage, min_width: u32, max_width: u32) -> StrikeImage {
+    let width = image.width();
+    if width == 0 || width >= min_width {
+        return image;
+    }
+    let factor = ((min_width + width - 1) / width).min(max_width / width);
+    if factor <= 1 {
+        return image;
+    }
+    StrikeImage::from_fn(width * factor, image.height() * factor, |x, y| {
+        *image.get_pixel(x / factor, y / factor)
+    })
+}
+
+// Optionally strip trailing whitespace from each line.
+fn trim_maybe(contents: &str, trim: bool) -> Cow<'_, str> {
+    if trim {
+        Cow::from(
+            contents
+                .split('\n')
+                .map(|line| line.trim_end())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        )
+    } else {
+        Cow::from(contents)
+    }
+}
+
+fn parse_option_value<T: FromStr>(option: &str, value: &str) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("invalid value for option '{}'", option))
+}
+
+fn decode_hex(contents: &str) -> Result<Vec<u8>> {
+    if contents.len() % 2 != 0 {
+        bail!("odd number of hex digits");
+    }
+    (0..contents.len())
+        .step_by(2)
+        .map(|i| {
+            contents
+                .get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .with_context(|| format!("invalid hex digits at offset {}", i))
+        })
+        .collect()
+}
+
+fn base64_maybe_decode(contents: &str, base64: bool) -> Result<Cow<'_, [u8]>> {
     if base64 {
         Ok(Cow::from(
             base64::engine::general_purpose::STANDARD
@@ -296,6 +1202,7 @@ fn base64_maybe_decode(contents: &str, base64: bool) -> Result<Cow<[u8]>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Cursor;
 
     #[test]
     fn code_block_parse_success() {
@@ -307,12 +1214,84 @@ mod tests {
                 "text black",
                 CodeBlockConfig::Text(TextBlock {
                     format: Format::new(),
+                    ..Default::default()
+                }),
+            ),
+            ("beep", CodeBlockConfig::Beep(BeepBlock::default())),
+            (
+                "image bicolor levels=3",
+                CodeBlockConfig::Image(ImageBlock {
+                    bicolor: true,
+                    levels: 3,
+                    ..Default::default()
+                }),
+            ),
+            (
+                "image minwidth=150",
+                CodeBlockConfig::Image(ImageBlock {
+                    min_width: 150,
+                    ..Default::default()
+                }),
+            ),
+            (
+                "image autoinvert",
+                CodeBlockConfig::Image(ImageBlock {
+                    auto_invert: Some(50),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "image autoinvert=30",
+                CodeBlockConfig::Image(ImageBlock {
+                    auto_invert: Some(30),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "beep count=3 off=50",
+                CodeBlockConfig::Beep(BeepBlock {
+                    count: 3,
+                    on: BEEP_DEFAULT_TIME,
+                    off: 50,
+                }),
+            ),
+            (
+                "text shade",
+                CodeBlockConfig::Text(TextBlock {
+                    format: Format::new().with_red(true).with_shade(true),
+                    ..Default::default()
                 }),
             ),
             (
                 " text  black  bold ",
                 CodeBlockConfig::Text(TextBlock {
                     format: Format::new().with_flags(FormatFlags::EMPHASIZED),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "text doublewidth size=2",
+                CodeBlockConfig::Text(TextBlock {
+                    format: Format::new()
+                        .with_red(true)
+                        .with_flags(FormatFlags::DOUBLE_HEIGHT),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "text size=4",
+                CodeBlockConfig::Text(TextBlock {
+                    format: Format::new()
+                        .with_red(true)
+                        .with_flags(FormatFlags::DOUBLE_HEIGHT | FormatFlags::DOUBLE_WIDTH),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "text trim",
+                CodeBlockConfig::Text(TextBlock {
+                    trim: true,
+                    ..Default::default()
                 }),
             ),
         ];
@@ -325,13 +1304,627 @@ mod tests {
     fn code_block_parse_error() {
         let tests = [
             "text bold blah",
+            "text size=0",
+            "text size=5",
+            "text size",
+            "image base64 black=foo.png",
+            "image red=foo.png bicolor",
+            "image black",
+            "menu bold",
+            "beep count",
+            "beep count=x",
+            "beep count=300",
+            "beep foo=1",
             "image foo",
+            "image levels=0",
+            "image levels=5",
+            "image levels=x",
+            "image minwidth=-1",
+            "image autoinvert=101",
+            "image autoinvert=x",
+            "image black=foo.png autoinvert",
+            "image black=foo.png levels=2",
+            "bitimage foo",
+            "title scale=0",
+            "title scale=9",
+            "title size=2",
             "bitmap foo",
             "code128 foo",
             "qrcode foo",
+            "qrgrid columns=0",
+            "qrgrid columns",
+            "table minwidth=0",
+            "table overflow=wrap",
+            "table widths=",
+            "table widths=4,x",
+            "table widths=0,*",
         ];
         for info in tests {
             CodeBlockConfig::from_info(info).unwrap_err();
         }
     }
+
+    #[test]
+    fn code_block_options() {
+        for (language, options) in [
+            ("beep", BeepBlock::OPTIONS),
+            ("bitimage", BitImageBlock::OPTIONS),
+            ("bitmap", BitmapBlock::OPTIONS),
+            ("code128", Code128Block::OPTIONS),
+            ("image", ImageBlock::OPTIONS),
+            ("menu", MenuBlock::OPTIONS),
+            ("qrcode", QrCodeBlock::OPTIONS),
+            ("qrgrid", QrGridBlock::OPTIONS),
+            ("table", TableBlock::OPTIONS),
+            ("text", TextBlock::OPTIONS),
+            ("title", TitleBlock::OPTIONS),
+        ] {
+            // every listed option is accepted
+            for option in options {
+                let option = option.replace("=N", "=1").replace("=PATH", "=foo.png");
+                CodeBlockConfig::from_info(&format!("{} {}", language, option)).unwrap();
+            }
+            // unknown options report the block type and the valid options
+            let err = CodeBlockConfig::from_info(&format!("{} bogus", language)).unwrap_err();
+            let message = format!("{:#}", err);
+            assert!(message.contains(language), "{}", message);
+            assert!(message.contains("'bogus'"), "{}", message);
+            for option in options {
+                assert!(message.contains(option), "{}", message);
+            }
+        }
+    }
+
+    #[test]
+    fn image_levels() {
+        let strikes = |levels| {
+            let block = ImageBlock {
+                levels,
+                ..Default::default()
+            };
+            let image = block.image("P2 4 1 255 255 128 80 0", 200).unwrap();
+            image.pixels().map(|p| p.0).collect::<Vec<_>>()
+        };
+        assert_eq!(strikes(2), [[0, 0], [1, 0], [1, 0], [2, 0]]);
+        assert_eq!(strikes(4), [[0, 0], [2, 0], [3, 0], [4, 0]]);
+        // 64 is equidistant from the 1-strike gray (128) and the 2-strike
+        // black (0); the lighter entry wins every time
+        for _ in 0..10 {
+            let block = ImageBlock {
+                levels: 2,
+                ..Default::default()
+            };
+            let image = block.image("P2 2 1 255 64 255", 200).unwrap();
+            assert_eq!(image.get_pixel(0, 0).0, [1, 0]);
+        }
+    }
+
+    #[test]
+    fn image_narrow() {
+        // too narrow for the dithering without padding
+        let image = ImageBlock::default()
+            .image("P2 1 2 255 0 255", 200)
+            .unwrap();
+        assert_eq!(
+            image.pixels().map(|p| p.0).collect::<Vec<_>>(),
+            [[1, 0], [0, 0]]
+        );
+        assert_eq!(
+            ImageBlock::default()
+                .image("P2 1 0 255", 200)
+                .unwrap()
+                .dimensions(),
+            (1, 0)
+        );
+    }
+
+    #[test]
+    fn image_correct_aspect() {
+        let pgm = format!("P2 20 20 255 {}", "0 ".repeat(400));
+        let block = ImageBlock::default();
+        assert_eq!(block.image(&pgm, 200).unwrap().dimensions(), (20, 20));
+        let block = ImageBlock {
+            correct_aspect: true,
+            ..Default::default()
+        };
+        let image = block.image(&pgm, 200).unwrap();
+        assert_eq!(image.dimensions(), (20, 18));
+        assert!(image.pixels().all(|p| p.0 == [1, 0]));
+    }
+
+    #[test]
+    fn image_auto_invert() {
+        // mostly dark, with one white pixel of "text"
+        let dark = "P2 2 2 255 0 0 0 255";
+        let printed = |block: &ImageBlock, pgm: &str| {
+            block
+                .image(pgm, 200)
+                .unwrap()
+                .pixels()
+                .map(|p| p.0[0])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(printed(&ImageBlock::default(), dark), [1, 1, 1, 0]);
+        let block = ImageBlock {
+            auto_invert: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(printed(&block, dark), [0, 0, 0, 1]);
+        // already mostly light
+        assert_eq!(printed(&block, "P2 2 2 255 255 255 255 0"), [0, 0, 0, 1]);
+        // mean luminance is 25%
+        let block = ImageBlock {
+            auto_invert: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(printed(&block, dark), [1, 1, 1, 0]);
+        let block = ImageBlock {
+            auto_invert: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(printed(&block, dark), [0, 0, 0, 1]);
+        // empty images are left alone
+        assert_eq!(mean_luminance(&RgbImage::new(0, 0)), 255);
+    }
+
+    #[test]
+    fn image_upscale() {
+        let image = ImageBlock::default()
+            .image("P1 3 2 1 0 1 0 1 0", 200)
+            .unwrap();
+        let pixels = |image: &StrikeImage| image.pixels().map(|p| p.0[0]).collect::<Vec<_>>();
+
+        assert_eq!(pixels(&upscale(image.clone(), 0, 200)), pixels(&image));
+        assert_eq!(pixels(&upscale(image.clone(), 3, 200)), pixels(&image));
+        let large = upscale(image.clone(), 5, 200);
+        assert_eq!(large.dimensions(), (6, 4));
+        assert_eq!(
+            pixels(&large),
+            [
+                1, 1, 0, 0, 1, 1, //
+                1, 1, 0, 0, 1, 1, //
+                0, 0, 1, 1, 0, 0, //
+                0, 0, 1, 1, 0, 0,
+            ]
+        );
+        assert_eq!(upscale(image.clone(), 12, 200).dimensions(), (12, 8));
+        // capped at the line width
+        assert_eq!(upscale(image.clone(), 1000, 200).dimensions(), (198, 132));
+        assert_eq!(upscale(image, 1000, 5).dimensions(), (3, 2));
+    }
+
+    #[test]
+    fn image_fit() {
+        let fit = ImageBlock {
+            fit: true,
+            ..Default::default()
+        };
+        let black = format!("P2 400 100 255 {}", "0 ".repeat(400 * 100));
+        let image = fit.image(&black, 200).unwrap();
+        assert_eq!(image.dimensions(), (200, 50));
+        assert!(image.pixels().all(|p| p.0 == [1, 0]));
+        // too wide without fit, for write_image() to reject
+        assert_eq!(
+            ImageBlock::default()
+                .image(&black, 200)
+                .unwrap()
+                .dimensions(),
+            (400, 100)
+        );
+        // narrower images are unchanged
+        assert_eq!(fit.image(&black, 400).unwrap().dimensions(), (400, 100));
+
+        let block = ImageBlock {
+            bicolor: true,
+            fit: true,
+            ..Default::default()
+        };
+        let red = format!("P3 400 2 255 {}", "255 0 0 ".repeat(400 * 2));
+        let image = block.image(&red, 200).unwrap();
+        assert_eq!(image.dimensions(), (200, 1));
+        assert!(image.pixels().all(|p| p.0 == [0, 1]));
+
+        let image = RgbImage::new(3, 3);
+        assert_eq!(fit_width(image.clone(), 2).dimensions(), (2, 2));
+        assert_eq!(fit_width(image, 0).dimensions(), (3, 3));
+    }
+
+    #[test]
+    fn image_layers() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("mintmark-{}-{}", std::process::id(), name));
+        std::fs::write(path("black.pbm"), "P1 3 1 1 0 1").unwrap();
+        std::fs::write(path("red.pbm"), "P1 3 1 0 1 1").unwrap();
+        std::fs::write(path("tall.pbm"), "P1 3 2 0 0 0 0 0 0").unwrap();
+
+        let block = ImageBlock {
+            black: Some(path("black.pbm")),
+            red: Some(path("red.pbm")),
+            ..Default::default()
+        };
+        let image = block.image("", 200).unwrap();
+        assert_eq!(
+            image.pixels().map(|p| p.0).collect::<Vec<_>>(),
+            [[1, 0], [0, 1], [1, 1]]
+        );
+        block.image("foo", 200).unwrap_err();
+
+        let block = ImageBlock {
+            red: Some(path("red.pbm")),
+            ..Default::default()
+        };
+        let image = block.image("", 200).unwrap();
+        assert_eq!(
+            image.pixels().map(|p| p.0).collect::<Vec<_>>(),
+            [[0, 0], [0, 1], [0, 1]]
+        );
+
+        // layers are scaled before thresholding
+        let block = ImageBlock {
+            black: Some(path("black.pbm")),
+            red: Some(path("red.pbm")),
+            fit: true,
+            ..Default::default()
+        };
+        assert_eq!(block.image("", 2).unwrap().dimensions(), (2, 1));
+
+        let block = ImageBlock {
+            black: Some(path("black.pbm")),
+            red: Some(path("tall.pbm")),
+            ..Default::default()
+        };
+        block.image("", 200).unwrap_err();
+
+        for name in ["black.pbm", "red.pbm", "tall.pbm"] {
+            std::fs::remove_file(path(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn image_layer_paths() {
+        let dir = std::env::temp_dir().join(format!("mintmark-{}-layers", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::create_dir(dir.join("art")).unwrap();
+        std::fs::write(dir.join("art/logo.pbm"), "P1 1 1 1").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("escape")).unwrap();
+
+        let resolve = |info: &str, image_dir: Option<&Path>| {
+            let mut config = CodeBlockConfig::from_info(info).unwrap();
+            config.resolve_paths(image_dir).map(|_| config)
+        };
+        let dir_canon = dir.canonicalize().unwrap();
+        match resolve("image black=art/logo.pbm red=./art/logo.pbm", Some(&dir)).unwrap() {
+            CodeBlockConfig::Image(block) => {
+                assert_eq!(block.black.unwrap(), dir_canon.join("art/logo.pbm"));
+                assert_eq!(block.red.unwrap(), dir_canon.join("art/logo.pbm"));
+            }
+            config => panic!("unexpected config {:?}", config),
+        }
+        // no image directory
+        resolve("image black=art/logo.pbm", None).unwrap_err();
+        // other blocks don't need one
+        resolve("image", None).unwrap();
+        resolve("text", None).unwrap();
+        for bad in [
+            "image black=/etc/passwd",
+            "image red=../etc/passwd",
+            "image black=art/../../etc/passwd",
+            "image black=escape",
+            "image black=missing.pbm",
+        ] {
+            resolve(bad, Some(&dir)).unwrap_err();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn menu() {
+        assert_eq!(menu_line("Coffee", "$5.00", 20), "Coffee ....... $5.00\n");
+        assert_eq!(menu_line("", "$5.00", 20), ".............. $5.00\n");
+        assert_eq!(
+            menu_line("Large iced caramel latte", "$5.00", 20),
+            "Large iced caramel\nlatte ........ $5.00\n"
+        );
+        assert_eq!(
+            menu_line("Large iced caramel", "$5.00", 20),
+            "Large iced caramel\n.............. $5.00\n"
+        );
+        assert_eq!(
+            menu_line("Supercalifragilisticexpialidocious", "$5", 20),
+            "Supercalifragilistic\nexpialidocious .. $5\n"
+        );
+        for line in menu_line("Coffee and a very long pastry name", "$12.50", 32).lines() {
+            assert!(line.len() <= 32);
+        }
+        assert_eq!(
+            menu_line("Coffee and a very long pastry name", "$12.50", 32)
+                .lines()
+                .last()
+                .unwrap()
+                .len(),
+            32
+        );
+    }
+
+    #[test]
+    fn code128_labeled() {
+        let render = |contents| -> Result<Vec<u8>> {
+            let mut output = std::io::Cursor::new(Vec::new());
+            let mut renderer = Renderer::new(&mut output);
+            let block = Code128Block {
+                labeled: true,
+                ..Default::default()
+            };
+            block.render(&mut renderer, contents)?;
+            renderer.print()?;
+            drop(renderer);
+            Ok(output.into_inner())
+        };
+        let output = render("SKU:\n12345\n").unwrap();
+        let find = |needle: &[u8]| output.windows(needle.len()).position(|w| w == needle);
+        let label = find(b"  SKU:\r").unwrap();
+        let barcode = find(b"\x1b*").unwrap();
+        let value = find(b"  12345\r").unwrap();
+        assert!(label < barcode && barcode < value);
+        render("12345\n").unwrap_err();
+    }
+
+    #[test]
+    fn width_limits() {
+        let error = |result: Result<StrikeImage>| format!("{:#}", result.unwrap_err());
+
+        assert_eq!(
+            code128_image(&"1".repeat(15), false, 200).unwrap().width(),
+            200
+        );
+        let message = error(code128_image(&"1".repeat(16), false, 200));
+        assert!(message.contains("max 15 characters"), "{}", message);
+        code128_image("1", false, 46).unwrap();
+        let message = error(code128_image("1", false, 45));
+        assert!(message.contains("max 0 characters"), "{}", message);
+
+        // version 18, the largest that fits, holds 718 bytes
+        let data = "a".repeat(719);
+        assert_eq!(
+            qrcode_image(&data.as_bytes()[..718], false, 200)
+                .unwrap()
+                .width(),
+            194
+        );
+        let message = error(qrcode_image(data.as_bytes(), false, 200));
+        assert!(message.contains("max 718 bytes"), "{}", message);
+        // denser encodings hold more
+        let message = error(qrcode_image("1".repeat(3000).as_bytes(), false, 200));
+        assert!(message.contains("max 1725 bytes"), "{}", message);
+        // version 1 holds 17 bytes
+        assert_eq!(
+            qrcode_image(&data.as_bytes()[..17], false, 58)
+                .unwrap()
+                .width(),
+            58
+        );
+        let message = error(qrcode_image(&data.as_bytes()[..18], false, 58));
+        assert!(message.contains("max 17 bytes"), "{}", message);
+        let message = error(qrcode_image(b"a", false, 57));
+        assert!(message.contains("max 0 bytes"), "{}", message);
+        // longer than the largest QR code
+        let message = error(qrcode_image("a".repeat(3000).as_bytes(), false, 200));
+        assert!(message.contains("max 718 bytes"), "{}", message);
+    }
+
+    #[test]
+    fn qrcode_base64() {
+        fn output(
+            render: impl Fn(&mut Renderer<&mut Cursor<Vec<u8>>>) -> Result<()>,
+        ) -> Result<Vec<u8>> {
+            let mut output = Cursor::new(Vec::new());
+            let mut renderer = Renderer::new(&mut output);
+            render(&mut renderer)?;
+            renderer.print()?;
+            drop(renderer);
+            Ok(output.into_inner())
+        }
+        let block = QrCodeBlock {
+            base64: true,
+            ..Default::default()
+        };
+        // binary payload, with the base64 wrapped across lines
+        let data = [0x00, 0xff, 0xfe, b'B', b'E', b'G', b'I', b'N', 0x80];
+        let expected = output(|renderer| renderer.write_image(&qrcode_image(&data, false, 200)?));
+        assert_eq!(
+            output(|renderer| block.render(renderer, "AP/+QkVH\nSU6A\n")).unwrap(),
+            expected.unwrap()
+        );
+        // text mode is the default
+        assert_ne!(
+            output(|renderer| QrCodeBlock::default().render(renderer, "AP/+QkVHSU6A")).unwrap(),
+            output(|renderer| block.render(renderer, "AP/+QkVHSU6A")).unwrap()
+        );
+
+        let err = output(|renderer| block.render(renderer, "AP/+QkVH!")).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("decoding base64"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn qrgrid() {
+        let render = |block: QrGridBlock, contents| -> Result<Vec<u8>> {
+            let mut output = std::io::Cursor::new(Vec::new());
+            let mut renderer = Renderer::new(&mut output);
+            block.render(&mut renderer, contents)?;
+            renderer.print()?;
+            drop(renderer);
+            Ok(output.into_inner())
+        };
+        let count = |haystack: &[u8], needle: &[u8]| {
+            haystack
+                .windows(needle.len())
+                .filter(|w| *w == needle)
+                .count()
+        };
+        let contents = "one | First\ntwo\nthree|Third\n";
+        let output = render(QrGridBlock::default(), contents).unwrap();
+        // two rows of codes, each a full line wide
+        let row_prologue = [b"\x1b*\x00".as_slice(), &200u16.to_le_bytes()].concat();
+        assert!(count(&output, &row_prologue) > 0);
+        assert!(count(&output, b"First") == 1 && count(&output, b"Third") == 1);
+        let first = output.windows(5).position(|w| w == b"First").unwrap();
+        let third = output.windows(5).position(|w| w == b"Third").unwrap();
+        let last_image = output.windows(2).rposition(|w| w == b"\x1b*").unwrap();
+        assert!(first < last_image && last_image < third);
+
+        // too many columns for the code size
+        let block = QrGridBlock {
+            columns: 5,
+            ..Default::default()
+        };
+        render(block, contents).unwrap_err();
+    }
+
+    #[test]
+    fn bit_image() {
+        let pixels = |image: &StrikeImage| image.pixels().map(|p| p.0[0]).collect::<Vec<_>>();
+        let image = BitImageBlock::default()
+            .image("10\nC0 80\n\n01c0\n")
+            .unwrap();
+        assert_eq!(image.dimensions(), (10, 2));
+        assert_eq!(
+            pixels(&image),
+            [
+                1, 1, 0, 0, 0, 0, 0, 0, 1, 0, //
+                0, 0, 0, 0, 0, 0, 0, 1, 1, 1,
+            ]
+        );
+
+        let block = BitImageBlock {
+            base64: true,
+            bold: true,
+        };
+        let image = block.image("4\n8A==\noA==\n").unwrap();
+        assert_eq!(pixels(&image), [2, 2, 2, 2, 2, 0, 2, 0]);
+
+        // width only
+        assert_eq!(
+            BitImageBlock::default().image("8").unwrap().dimensions(),
+            (8, 0)
+        );
+        for bad in [
+            "",
+            "x\nff",
+            "0\n",
+            "201\n",
+            "8\nfff",
+            "8\nffff",
+            "9\nff",
+            "8\ngg",
+            "8\n\u{e9}f",
+        ] {
+            BitImageBlock::default().image(bad).unwrap_err();
+        }
+        block.image("8\n!!!!\n").unwrap_err();
+        // the full width is allowed
+        let full = format!("200\n{}", "ff".repeat(25));
+        assert_eq!(
+            BitImageBlock::default().image(&full).unwrap().dimensions(),
+            (200, 1)
+        );
+    }
+
+    #[test]
+    fn title() {
+        let rows = |image: &StrikeImage| {
+            image
+                .rows()
+                .map(|row| {
+                    row.map(|p| match p.0 {
+                        [0, 0] => ' ',
+                        [1, 0] => '#',
+                        [2, 0] => 'B',
+                        [0, 1] => 'r',
+                        _ => '?',
+                    })
+                    .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let block = TitleBlock {
+            scale: 1,
+            ..Default::default()
+        };
+        let image = block.image("Hi\n", 200).unwrap();
+        assert_eq!(
+            rows(&image),
+            [
+                "#   #   #  ",
+                "#   #      ",
+                "#   #  ##  ",
+                "#####   #  ",
+                "#   #   #  ",
+                "#   #   #  ",
+                "#   #  ### ",
+            ]
+        );
+
+        // lines are centered on each other and scaled
+        let block = TitleBlock {
+            scale: 2,
+            red: true,
+            ..Default::default()
+        };
+        let image = block.image("-\n---", 200).unwrap();
+        assert_eq!(image.dimensions(), (17 * 2, 16 * 2));
+        let rows = rows(&image);
+        assert_eq!(
+            rows[6],
+            format!("{}{}{}", " ".repeat(12), "r".repeat(10), " ".repeat(12))
+        );
+        assert_eq!(rows[7], rows[6]);
+        assert_eq!(rows[24], format!("{0}  {0}  {0}", "r".repeat(10)));
+        assert_eq!(rows[25], rows[24]);
+        for (y, row) in rows.iter().enumerate() {
+            if ![6, 7, 24, 25].contains(&y) {
+                assert!(!row.contains('r'), "{}", y);
+            }
+        }
+
+        let block = TitleBlock {
+            bold: true,
+            ..Default::default()
+        };
+        // printed as '?'
+        let image = block.image("\u{e9}", 200).unwrap();
+        assert_eq!(image.dimensions(), (15, 21));
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0]);
+        assert_eq!(image.get_pixel(0, 3).0, [2, 0]);
+        assert_eq!(image.get_pixel(3, 3).0, [0, 0]);
+        assert_eq!(image.get_pixel(6, 18).0, [2, 0]);
+
+        // 11 characters fit at scale 3, but not 12
+        let block = TitleBlock::default();
+        assert_eq!(block.image("Hello world", 200).unwrap().width(), 195);
+        let err = block.image("Hello world!", 200).unwrap_err();
+        assert!(format!("{:#}", err).contains("max 11 characters"));
+        block.image("Hello world", 194).unwrap_err();
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        let contents = "# # \n #  \t\n\n";
+        assert_eq!(trim_maybe(contents, false), contents);
+        assert_eq!(trim_maybe(contents, true), "# #\n #\n\n");
+
+        let image = BitmapBlock::default().image(contents).unwrap();
+        assert_eq!(image.dimensions(), (5, 2));
+        let image = BitmapBlock {
+            trim: true,
+            ..Default::default()
+        }
+        .image(contents)
+        .unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+    }
 }
@@ -0,0 +1,250 @@
+/*
+ * Copyright 2026 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! 5x7 bitmap font for text rasterized into images, drawn for mintmark.
+
+pub(crate) const GLYPH_WIDTH: u32 = 5;
+pub(crate) const GLYPH_HEIGHT: u32 = 7;
+
+/// A glyph as rows of text, top to bottom, with '#' for each set pixel.
+pub(crate) type Glyph = [&'static str; GLYPH_HEIGHT as usize];
+
+/// Glyphs for printable ASCII, starting at space.  Lowercase descenders
+/// share the bottom rows with the baseline, since there's no room below.
+#[rustfmt::skip]
+const GLYPHS: [Glyph; 95] = [
+    // space
+    ["     ", "     ", "     ", "     ", "     ", "     ", "     "],
+    // !
+    ["  #  ", "  #  ", "  #  ", "  #  ", "  #  ", "     ", "  #  "],
+    // "
+    [" # # ", " # # ", "     ", "     ", "     ", "     ", "     "],
+    // #
+    [" # # ", " # # ", "#####", " # # ", "#####", " # # ", " # # "],
+    // $
+    ["  #  ", " ####", "# #  ", " ### ", "  # #", "#### ", "  #  "],
+    // %
+    ["##   ", "##  #", "   # ", "  #  ", " #   ", "#  ##", "   ##"],
+    // &
+    [" ##  ", "#  # ", "# #  ", " #   ", "# # #", "#  # ", " ## #"],
+    // '
+    ["  #  ", "  #  ", " #   ", "     ", "     ", "     ", "     "],
+    // (
+    ["   # ", "  #  ", " #   ", " #   ", " #   ", "  #  ", "   # "],
+    // )
+    [" #   ", "  #  ", "   # ", "   # ", "   # ", "  #  ", " #   "],
+    // *
+    ["     ", "  #  ", "# # #", " ### ", "# # #", "  #  ", "     "],
+    // +
+    ["     ", "  #  ", "  #  ", "#####", "  #  ", "  #  ", "     "],
+    // ,
+    ["     ", "     ", "     ", "     ", " ##  ", "  #  ", " #   "],
+    // -
+    ["     ", "     ", "     ", "#####", "     ", "     ", "     "],
+    // .
+    ["     ", "     ", "     ", "     ", "     ", " ##  ", " ##  "],
+    // /
+    ["     ", "    #", "   # ", "  #  ", " #   ", "#    ", "     "],
+    // 0
+    [" ### ", "#   #", "#  ##", "# # #", "##  #", "#   #", " ### "],
+    // 1
+    ["  #  ", " ##  ", "  #  ", "  #  ", "  #  ", "  #  ", " ### "],
+    // 2
+    [" ### ", "#   #", "    #", "   # ", "  #  ", " #   ", "#####"],
+    // 3
+    ["#####", "   # ", "  #  ", "   # ", "    #", "#   #", " ### "],
+    // 4
+    ["   # ", "  ## ", " # # ", "#  # ", "#####", "   # ", "   # "],
+    // 5
+    ["#####", "#    ", "#### ", "    #", "    #", "#   #", " ### "],
+    // 6
+    ["  ## ", " #   ", "#    ", "#### ", "#   #", "#   #", " ### "],
+    // 7
+    ["#####", "    #", "   # ", "  #  ", " #   ", " #   ", " #   "],
+    // 8
+    [" ### ", "#   #", "#   #", " ### ", "#   #", "#   #", " ### "],
+    // 9
+    [" ### ", "#   #", "#   #", " ####", "    #", "   # ", " ##  "],
+    // :
+    ["     ", " ##  ", " ##  ", "     ", " ##  ", " ##  ", "     "],
+    // ;
+    ["     ", " ##  ", " ##  ", "     ", " ##  ", "  #  ", " #   "],
+    // <
+    ["   # ", "  #  ", " #   ", "#    ", " #   ", "  #  ", "   # "],
+    // =
+    ["     ", "     ", "#####", "     ", "#####", "     ", "     "],
+    // >
+    [" #   ", "  #  ", "   # ", "    #", "   # ", "  #  ", " #   "],
+    // ?
+    [" ### ", "#   #", "    #", "   # ", "  #  ", "     ", "  #  "],
+    // @
+    [" ### ", "#   #", "    #", " ## #", "# # #", "# # #", " ### "],
+    // A
+    [" ### ", "#   #", "#   #", "#####", "#   #", "#   #", "#   #"],
+    // B
+    ["#### ", "#   #", "#   #", "#### ", "#   #", "#   #", "#### "],
+    // C
+    [" ### ", "#   #", "#    ", "#    ", "#    ", "#   #", " ### "],
+    // D
+    ["###  ", "#  # ", "#   #", "#   #", "#   #", "#  # ", "###  "],
+    // E
+    ["#####", "#    ", "#    ", "#### ", "#    ", "#    ", "#####"],
+    // F
+    ["#####", "#    ", "#    ", "#### ", "#    ", "#    ", "#    "],
+    // G
+    [" ### ", "#   #", "#    ", "# ###", "#   #", "#   #", " ####"],
+    // H
+    ["#   #", "#   #", "#   #", "#####", "#   #", "#   #", "#   #"],
+    // I
+    [" ### ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", " ### "],
+    // J
+    ["  ###", "   # ", "   # ", "   # ", "   # ", "#  # ", " ##  "],
+    // K
+    ["#   #", "#  # ", "# #  ", "##   ", "# #  ", "#  # ", "#   #"],
+    // L
+    ["#    ", "#    ", "#    ", "#    ", "#    ", "#    ", "#####"],
+    // M
+    ["#   #", "## ##", "# # #", "# # #", "#   #", "#   #", "#   #"],
+    // N
+    ["#   #", "#   #", "##  #", "# # #", "#  ##", "#   #", "#   #"],
+    // O
+    [" ### ", "#   #", "#   #", "#   #", "#   #", "#   #", " ### "],
+    // P
+    ["#### ", "#   #", "#   #", "#### ", "#    ", "#    ", "#    "],
+    // Q
+    [" ### ", "#   #", "#   #", "#   #", "# # #", "#  # ", " ## #"],
+    // R
+    ["#### ", "#   #", "#   #", "#### ", "# #  ", "#  # ", "#   #"],
+    // S
+    [" ####", "#    ", "#    ", " ### ", "    #", "    #", "#### "],
+    // T
+    ["#####", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  "],
+    // U
+    ["#   #", "#   #", "#   #", "#   #", "#   #", "#   #", " ### "],
+    // V
+    ["#   #", "#   #", "#   #", "#   #", "#   #", " # # ", "  #  "],
+    // W
+    ["#   #", "#   #", "#   #", "# # #", "# # #", "# # #", " # # "],
+    // X
+    ["#   #", "#   #", " # # ", "  #  ", " # # ", "#   #", "#   #"],
+    // Y
+    ["#   #", "#   #", " # # ", "  #  ", "  #  ", "  #  ", "  #  "],
+    // Z
+    ["#####", "    #", "   # ", "  #  ", " #   ", "#    ", "#####"],
+    // [
+    [" ### ", " #   ", " #   ", " #   ", " #   ", " #   ", " ### "],
+    // \
+    ["     ", "#    ", " #   ", "  #  ", "   # ", "    #", "     "],
+    // ]
+    [" ### ", "   # ", "   # ", "   # ", "   # ", "   # ", " ### "],
+    // ^
+    ["  #  ", " # # ", "#   #", "     ", "     ", "     ", "     "],
+    // _
+    ["     ", "     ", "     ", "     ", "     ", "     ", "#####"],
+    // `
+    [" #   ", "  #  ", "   # ", "     ", "     ", "     ", "     "],
+    // a
+    ["     ", "     ", " ### ", "    #", " ####", "#   #", " ####"],
+    // b
+    ["#    ", "#    ", "# ## ", "##  #", "#   #", "#   #", "#### "],
+    // c
+    ["     ", "     ", " ### ", "#    ", "#    ", "#   #", " ### "],
+    // d
+    ["    #", "    #", " ## #", "#  ##", "#   #", "#   #", " ####"],
+    // e
+    ["     ", "     ", " ### ", "#   #", "#####", "#    ", " ### "],
+    // f
+    ["  ## ", " #  #", " #   ", "###  ", " #   ", " #   ", " #   "],
+    // g
+    ["     ", " ####", "#   #", "#   #", " ####", "    #", " ### "],
+    // h
+    ["#    ", "#    ", "# ## ", "##  #", "#   #", "#   #", "#   #"],
+    // i
+    ["  #  ", "     ", " ##  ", "  #  ", "  #  ", "  #  ", " ### "],
+    // j
+    ["   # ", "     ", "  ## ", "   # ", "   # ", "#  # ", " ##  "],
+    // k
+    ["#    ", "#    ", "#  # ", "# #  ", "##   ", "# #  ", "#  # "],
+    // l
+    [" ##  ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", " ### "],
+    // m
+    ["     ", "     ", "## # ", "# # #", "# # #", "#   #", "#   #"],
+    // n
+    ["     ", "     ", "# ## ", "##  #", "#   #", "#   #", "#   #"],
+    // o
+    ["     ", "     ", " ### ", "#   #", "#   #", "#   #", " ### "],
+    // p
+    ["     ", "     ", "#### ", "#   #", "#### ", "#    ", "#    "],
+    // q
+    ["     ", "     ", " ####", "#   #", " ####", "    #", "    #"],
+    // r
+    ["     ", "     ", "# ## ", "##  #", "#    ", "#    ", "#    "],
+    // s
+    ["     ", "     ", " ####", "#    ", " ### ", "    #", "#### "],
+    // t
+    [" #   ", " #   ", "###  ", " #   ", " #   ", " #  #", "  ## "],
+    // u
+    ["     ", "     ", "#   #", "#   #", "#   #", "#  ##", " ## #"],
+    // v
+    ["     ", "     ", "#   #", "#   #", "#   #", " # # ", "  #  "],
+    // w
+    ["     ", "     ", "#   #", "#   #", "# # #", "# # #", " # # "],
+    // x
+    ["     ", "     ", "#   #", " # # ", "  #  ", " # # ", "#   #"],
+    // y
+    ["     ", "     ", "#   #", "#   #", " ####", "    #", " ### "],
+    // z
+    ["     ", "     ", "#####", "   # ", "  #  ", " #   ", "#####"],
+    // {
+    ["   # ", "  #  ", "  #  ", " #   ", "  #  ", "  #  ", "   # "],
+    // |
+    ["  #  ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  "],
+    // }
+    [" #   ", "  #  ", "  #  ", "   # ", "  #  ", "  #  ", " #   "],
+    // ~
+    ["     ", "     ", " #   ", "# # #", "   # ", "     ", "     "],
+];
+
+/// Get the glyph for a character.  Characters outside printable ASCII
+/// are drawn as '?', like in printed text.
+pub(crate) fn glyph(ch: char) -> &'static Glyph {
+    let index = match ch {
+        ' '..='~' => ch as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &GLYPHS[index]
+}
+
+/// Whether the pixel at (x, y) of a glyph is set.
+pub(crate) fn glyph_pixel(glyph: &Glyph, x: u32, y: u32) -> bool {
+    glyph[y as usize].as_bytes()[x as usize] == b'#'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyph_rows() {
+        for glyph in GLYPHS.iter() {
+            for row in glyph {
+                assert_eq!(row.len(), GLYPH_WIDTH as usize, "{:?}", glyph);
+                assert!(row.bytes().all(|b| b == b'#' || b == b' '), "{:?}", glyph);
+            }
+        }
+        assert_eq!(glyph('\u{e9}'), glyph('?'));
+    }
+}
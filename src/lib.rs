@@ -0,0 +1,1680 @@
+/*
+ * Copyright 2020-2022 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+mod codeblock;
+mod font;
+mod preview;
+mod render;
+mod status;
+mod strike;
+mod table;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Write as _};
+use std::io::{Read, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use codeblock::{code128_image, qrcode_image, CodeBlockConfig};
+use render::{Format, FormatFlags, Justification, Renderer, BEEP_DEFAULT_TIME};
+use strike::{StrikeColors, StrikeImage};
+use table::{Table, TableOptions};
+
+pub use preview::PreviewStyle;
+pub use render::{FontMetrics, PaperOut};
+
+/// Document-wide rendering options
+#[derive(Debug, Default)]
+pub struct RenderOptions {
+    /// Sound the buzzer this many times after printing
+    pub beep: Option<u8>,
+    /// Cut the paper after this many lines, at the next block boundary
+    pub lines_per_slip: Option<NonZeroUsize>,
+    /// Reorder right-to-left text
+    pub rtl: bool,
+    /// Print consecutive hard breaks as a single blank line
+    pub collapse_hard_breaks: bool,
+    /// Left margin in printer motion units (half-dots)
+    pub left_margin: u16,
+    /// Print a QR code linking to this URL at the end of the document
+    pub qr_link: Option<String>,
+    /// Caption printed below the QR link, if not empty
+    pub qr_caption: String,
+    /// Print a barcode identifying the document at the end
+    pub archive_code: Option<ArchiveCode>,
+    /// Print darkness from 1 (normal) to 3, or None for normal
+    pub darkness: Option<u8>,
+    /// ASCII characters after which long words can wrap, in addition to
+    /// spaces
+    pub break_chars: String,
+    /// Fail if the printer reports an error after printing
+    pub check_status: bool,
+    /// Refuse to print documents longer than this many lines
+    pub max_lines: Option<usize>,
+    /// Check the paper near-end sensor before sending the document
+    pub warn_low_paper: bool,
+    /// If paper is low, also print a note at the end of the slip
+    pub low_paper_note: bool,
+    /// Print column rulers for the narrow and wide fonts before the
+    /// document
+    pub ruler: bool,
+    /// Character widths of the printer's fonts
+    pub font_metrics: FontMetrics,
+    /// Start a new slip before each heading of this level (1-6)
+    pub split_on_heading: Option<u8>,
+    /// ASCII character printed at the end of soft-wrapped lines
+    pub wrap_marker: Option<char>,
+    /// Extra indent for the continuation of soft-wrapped lines, in
+    /// characters
+    pub wrap_indent: usize,
+    /// Identify the template in a footer line
+    pub template_id: Option<String>,
+    /// Add the start of the input's SHA-256 digest to the footer
+    pub footer_hash: bool,
+    /// Watch for the paper running out while printing
+    pub paper_out: Option<PaperOut>,
+    /// Called when PaperOut::Wait stops sending until paper is loaded
+    pub on_paper_wait: Option<fn()>,
+    /// Directory image blocks may load layer files from.  If None,
+    /// blocks that name files are refused.
+    pub image_dir: Option<PathBuf>,
+}
+
+/// A barcode for looking up or verifying a printed document
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveCode {
+    /// QR code of the SHA-256 digest of the input
+    Hash,
+    /// Code 128 barcode of the first 48 bits of the SHA-256 digest
+    ShortHash,
+    /// QR code of the entire input, if it fits
+    Content,
+}
+
+impl FromStr for ArchiveCode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "hash" => Self::Hash,
+            "short-hash" => Self::ShortHash,
+            "content" => Self::Content,
+            _ => bail!("unknown archive code type '{}'", s),
+        })
+    }
+}
+
+/// Information about a completed print job
+#[derive(Debug, Default)]
+pub struct RenderReport {
+    /// The paper near-end sensor tripped while printing
+    pub low_paper: bool,
+    /// Input that couldn't be printed as written
+    pub dropped: DroppedContent,
+}
+
+/// Counts of input that was left out of the printout or printed in
+/// degraded form
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct DroppedContent {
+    /// Raw HTML blocks and inline tags, which are skipped
+    pub html: usize,
+    /// Link destinations; only the link text is printed
+    pub link_urls: usize,
+    /// Images other than data URIs, printed as their alt text
+    pub images: usize,
+    /// Characters the printer can't print, replaced with '?'
+    pub replaced_chars: usize,
+}
+
+impl DroppedContent {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Add the counts from another job.
+    pub fn merge(&mut self, other: &Self) {
+        self.html += other.html;
+        self.link_urls += other.link_urls;
+        self.images += other.images;
+        self.replaced_chars += other.replaced_chars;
+    }
+}
+
+impl fmt::Display for DroppedContent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let items = [
+            (self.html, "raw HTML fragment(s) skipped"),
+            (self.link_urls, "link URL(s) not printed"),
+            (self.images, "image(s) printed as alt text"),
+            (self.replaced_chars, "unprintable character(s) replaced"),
+        ];
+        let mut first = true;
+        for (count, description) in items {
+            if count > 0 {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{} {}", count, description)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Render Markdown input to the printer.
+pub fn render(
+    input: &str,
+    options: &RenderOptions,
+    output: &mut (impl Read + Write),
+) -> Result<RenderReport> {
+    let mut renderer = new_renderer(output, options)?;
+    let mut report = render_document(&mut renderer, input, options)?;
+    if options.warn_low_paper {
+        // Check before sending anything, so the note can be added to the
+        // slip and counted against the line limit.  The sensor doesn't
+        // yet reflect the paper this document will use.
+        report.low_paper = renderer.status()?.low_paper();
+        if report.low_paper && options.low_paper_note {
+            renderer.write("\nPaper is running low\n")?;
+        }
+    }
+
+    // The last block may have filled the slip and already been cut
+    renderer.cut_if_slip_started();
+    if let Some(count) = options.beep {
+        renderer.beep(count, BEEP_DEFAULT_TIME, BEEP_DEFAULT_TIME)?;
+    }
+    check_max_lines(&renderer, options)?;
+    renderer.print()?;
+    if options.check_status {
+        renderer.status()?.check()?;
+    }
+
+    Ok(report)
+}
+
+/// Render Markdown input to a text approximation of the printout,
+/// without sending anything to a printer.
+pub fn preview(input: &str, options: &RenderOptions, style: PreviewStyle) -> Result<String> {
+    if options.check_status || options.warn_low_paper || options.paper_out.is_some() {
+        bail!("printer status isn't available when previewing");
+    }
+    let mut renderer = new_renderer(std::io::Cursor::new(Vec::new()), options)?;
+    renderer.set_preview(Some(style));
+    render_document(&mut renderer, input, options)?;
+    renderer.cut_if_slip_started();
+    check_max_lines(&renderer, options)?;
+    Ok(renderer.take_preview().expect("preview enabled"))
+}
+
+/// Count the lines the document would print, as limited by
+/// `RenderOptions::max_lines`, without sending anything.
+pub fn line_count(input: &str, options: &RenderOptions) -> Result<usize> {
+    let mut renderer = new_renderer(std::io::Cursor::new(Vec::new()), options)?;
+    render_document(&mut renderer, input, options)?;
+    renderer.cut_if_slip_started();
+    Ok(renderer.line_count())
+}
+
+fn new_renderer<F: Read + Write>(output: F, options: &RenderOptions) -> Result<Renderer<F>> {
+    let mut renderer = Renderer::new(output);
+    renderer.set_left_margin(options.left_margin)?;
+    renderer.set_lines_per_slip(options.lines_per_slip.map(NonZeroUsize::get));
+    renderer.set_rtl(options.rtl);
+    renderer.set_break_chars(&options.break_chars)?;
+    renderer.set_darkness(options.darkness.unwrap_or(1))?;
+    renderer.set_font_metrics(options.font_metrics)?;
+    renderer.set_wrap_style(options.wrap_marker, options.wrap_indent)?;
+    renderer.set_paper_out(options.paper_out);
+    renderer.set_paper_wait_callback(options.on_paper_wait);
+    Ok(renderer)
+}
+
+fn parser_options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options
+}
+
+// Render the document into the renderer's buffer, up to but not
+// including the final cut
+fn render_document(
+    renderer: &mut Renderer<impl Read + Write>,
+    input: &str,
+    options: &RenderOptions,
+) -> Result<RenderReport> {
+    if options.ruler {
+        write_rulers(renderer)?;
+    }
+    let mut dropped = render_markdown(renderer, input, options, &TableOptions::default())?;
+
+    if let Some(url) = &options.qr_link {
+        let image = qrcode_image(url.as_bytes(), false, renderer.line_pixels_image())
+            .context("QR link too long")?;
+        renderer.write_image(&image)?;
+        if !options.qr_caption.is_empty() {
+            renderer.set_format(renderer.format().with_justification(Justification::Center));
+            renderer.write(&format!("{}\n", options.qr_caption))?;
+            renderer.restore_format();
+        }
+    }
+
+    if let Some(code) = options.archive_code {
+        write_archive_code(renderer, input, code)?;
+    }
+
+    write_footer(renderer, input, options)?;
+
+    dropped.replaced_chars = renderer.replaced_chars();
+    Ok(RenderReport {
+        dropped,
+        ..Default::default()
+    })
+}
+
+// Render Markdown into the renderer's buffer.  Tables are laid out with
+// `table_options` unless they're in a table block of their own.
+fn render_markdown(
+    renderer: &mut Renderer<impl Read + Write>,
+    input: &str,
+    options: &RenderOptions,
+    table_options: &TableOptions,
+) -> Result<DroppedContent> {
+    let parser = Parser::new_ext(input, parser_options());
+    let mut code_block: Option<CodeBlockConfig> = None;
+    // Options and Markdown contents of a table block
+    let mut table_block: Option<(TableOptions, String)> = None;
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    let mut prev_hard_break = false;
+    let mut prev_html = false;
+    let mut inline_image = false;
+    let mut table: Option<Table> = None;
+    let mut dropped = DroppedContent::default();
+    for (event, _) in parser.into_offset_iter() {
+        let hard_break = matches!(event, Event::HardBreak);
+        let html = matches!(event, Event::Html(_));
+        match event {
+            Event::Start(tag) => {
+                match tag {
+                    Tag::Paragraph => {}
+                    Tag::Heading(level, _, _) => {
+                        if options.split_on_heading == Some(level as u8) {
+                            renderer.cut_if_slip_started();
+                        }
+                        // Center first.  This only takes effect at the
+                        // start of the line, so end tag handling needs to
+                        // specially account for it.
+                        renderer.set_format(
+                            renderer.format().with_justification(Justification::Center),
+                        );
+                        match level {
+                            HeadingLevel::H1 => {
+                                renderer.set_format(
+                                    renderer.format().with_unidirectional(true).with_flags(
+                                        FormatFlags::DOUBLE_HEIGHT
+                                            | FormatFlags::DOUBLE_WIDTH
+                                            | FormatFlags::EMPHASIZED
+                                            | FormatFlags::UNDERLINE,
+                                    ),
+                                );
+                            }
+                            HeadingLevel::H2 => {
+                                renderer.set_format(
+                                    renderer.format().with_unidirectional(true).with_flags(
+                                        FormatFlags::DOUBLE_HEIGHT
+                                            | FormatFlags::DOUBLE_WIDTH
+                                            | FormatFlags::EMPHASIZED,
+                                    ),
+                                );
+                            }
+                            HeadingLevel::H3 => {
+                                renderer.set_format(
+                                    renderer
+                                        .format()
+                                        .with_flags(
+                                            FormatFlags::EMPHASIZED | FormatFlags::UNDERLINE,
+                                        )
+                                        .without_flags(FormatFlags::NARROW),
+                                );
+                            }
+                            HeadingLevel::H4 => {
+                                renderer.set_format(
+                                    renderer
+                                        .format()
+                                        .with_flags(FormatFlags::EMPHASIZED)
+                                        .without_flags(FormatFlags::NARROW),
+                                );
+                            }
+                            HeadingLevel::H5 => {
+                                renderer.set_format(
+                                    renderer.format().with_flags(
+                                        FormatFlags::EMPHASIZED | FormatFlags::UNDERLINE,
+                                    ),
+                                );
+                            }
+                            _ => {
+                                renderer.set_format(
+                                    renderer.format().with_flags(FormatFlags::EMPHASIZED),
+                                );
+                            }
+                        }
+                    }
+                    Tag::BlockQuote => {
+                        renderer.set_format(renderer.format().with_added_indent(4));
+                    }
+                    Tag::CodeBlock(kind) => {
+                        let info = match kind {
+                            CodeBlockKind::Indented => "".into(),
+                            CodeBlockKind::Fenced(s) => s,
+                        };
+                        assert!(code_block.is_none());
+                        let mut config = CodeBlockConfig::from_info(&info)?;
+                        config.resolve_paths(options.image_dir.as_deref())?;
+                        match config {
+                            CodeBlockConfig::Table(block) => {
+                                table_block = Some((block.options, String::new()));
+                            }
+                            config => code_block = Some(config),
+                        }
+                    }
+                    Tag::List(first_item_number) => {
+                        lists.push(first_item_number);
+                    }
+                    Tag::Item => {
+                        let item = lists.last_mut().expect("non-empty list list");
+                        match *item {
+                            Some(n) => {
+                                let marker = format!("{:2}. ", n);
+                                renderer.write(&marker)?;
+                                renderer
+                                    .set_format(renderer.format().with_added_indent(marker.len()));
+                                *item.as_mut().unwrap() += 1;
+                            }
+                            None => {
+                                renderer.write("  - ")?;
+                                renderer.set_format(renderer.format().with_added_indent(4));
+                            }
+                        }
+                    }
+                    Tag::Emphasis => {
+                        renderer.set_format(renderer.format().with_italic());
+                    }
+                    Tag::Strong => {
+                        renderer.set_format(renderer.format().with_flags(FormatFlags::EMPHASIZED));
+                    }
+                    Tag::Strikethrough => {
+                        renderer.set_format(renderer.format().with_strikethrough(true));
+                    }
+                    Tag::Table(alignments) => {
+                        assert!(table.is_none());
+                        table = Some(Table::new(alignments, table_options.clone()));
+                    }
+                    Tag::TableCell => {
+                        table.as_mut().expect("cell outside table").start_cell();
+                    }
+                    Tag::TableHead | Tag::TableRow => {}
+                    // Unsupported containers.  Their contents arrive as
+                    // separate events and are printed as plain text.
+                    Tag::FootnoteDefinition(_) => {}
+                    // Images embedded as data URIs print inline, in
+                    // place of their alt text.  Table cells are buffered
+                    // as formatted text, so they get the alt text.
+                    Tag::Image(_, url, _) if url.starts_with("data:") && table.is_none() => {
+                        let image = data_uri_image(&url).context("loading inline image")?;
+                        renderer.write_inline_image(&image)?;
+                        inline_image = true;
+                    }
+                    // We can't print a destination, so links print as
+                    // their link text and other images as their alt text.
+                    Tag::Link(..) => {
+                        dropped.link_urls += 1;
+                    }
+                    Tag::Image(..) => {
+                        dropped.images += 1;
+                    }
+                    // Tags added by newer pulldown-cmark releases.  Ignore
+                    // them here and in Event::End so the format stack
+                    // stays balanced.
+                    #[allow(unreachable_patterns)]
+                    _ => {}
+                }
+            }
+            Event::End(tag) => match tag {
+                Tag::Paragraph => {
+                    renderer.write("\n\n")?;
+                    renderer.cut_if_slip_full();
+                }
+                Tag::Heading(_, _, _) => {
+                    // peel off everything but the centering command
+                    renderer.restore_format();
+                    renderer.write("\n\n")?;
+                    // peel off the centering command now that we're at
+                    // the start of a line
+                    renderer.restore_format();
+                    renderer.cut_if_slip_full();
+                }
+                Tag::BlockQuote => {
+                    renderer.restore_format();
+                }
+                Tag::CodeBlock(_) => {
+                    if let Some((table_options, contents)) = table_block.take() {
+                        dropped.merge(&render_markdown(
+                            renderer,
+                            &contents,
+                            options,
+                            &table_options,
+                        )?);
+                    } else {
+                        assert!(code_block.is_some());
+                        code_block = None;
+                    }
+                    renderer.cut_if_slip_full();
+                }
+                Tag::List(_first_item_number) => {
+                    lists.pop();
+                    renderer.write("\n")?;
+                    renderer.cut_if_slip_full();
+                }
+                Tag::Item => {
+                    renderer.restore_format();
+                    renderer.write("\n")?;
+                    renderer.cut_if_slip_full();
+                }
+                Tag::Emphasis => {
+                    renderer.restore_format();
+                }
+                Tag::Strong => {
+                    renderer.restore_format();
+                }
+                Tag::Strikethrough => {
+                    renderer.restore_format();
+                }
+                Tag::Table(_) => {
+                    table.take().expect("table ended").render(renderer)?;
+                    renderer.write("\n")?;
+                    renderer.cut_if_slip_full();
+                }
+                Tag::TableHead => {
+                    table.as_mut().expect("header outside table").end_header();
+                }
+                Tag::TableRow => {
+                    table.as_mut().expect("row outside table").end_row();
+                }
+                Tag::TableCell => {
+                    table.as_mut().expect("cell outside table").end_cell();
+                }
+                // Ignored in Event::Start too
+                Tag::FootnoteDefinition(_) | Tag::Link(..) => {}
+                Tag::Image(..) => {
+                    inline_image = false;
+                }
+                #[allow(unreachable_patterns)]
+                _ => {}
+            },
+            // Alt text of an image printed inline
+            Event::Text(_) | Event::Code(_) if inline_image => {}
+            Event::Text(contents) if table_block.is_some() => {
+                table_block.as_mut().unwrap().1.push_str(&contents);
+            }
+            Event::Text(contents)
+                if table
+                    .as_mut()
+                    .map_or(false, |t| t.push_text(&contents, &renderer.format())) => {}
+            Event::Code(contents)
+                if table.as_mut().map_or(false, |t| {
+                    t.push_text(&contents, &renderer.format().with_red(true))
+                }) => {}
+            Event::Text(contents) => {
+                if let Some(block) = code_block.as_ref() {
+                    block.render(renderer, &contents)?;
+                } else {
+                    renderer.write(&contents)?;
+                }
+            }
+            Event::Code(contents) => {
+                renderer.set_format(renderer.format().with_red(true));
+                renderer.write(&contents)?;
+                renderer.restore_format();
+            }
+            Event::SoftBreak => {
+                renderer.write(" ")?;
+            }
+            Event::HardBreak if options.collapse_hard_breaks && prev_hard_break => {
+                // already printed a blank line
+            }
+            Event::HardBreak => {
+                renderer.write("\n\n")?;
+            }
+            Event::Rule => {
+                renderer.cut();
+            }
+            // Raw HTML can't be rendered, footnotes aren't supported,
+            // and task lists print without their checkboxes.  HTML blocks
+            // arrive one line at a time, so count each run once.
+            Event::Html(_) if !prev_html => {
+                dropped.html += 1;
+            }
+            Event::Html(_) | Event::FootnoteReference(_) | Event::TaskListMarker(_) => {}
+            // Events added by newer pulldown-cmark releases
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+        prev_hard_break = hard_break;
+        prev_html = html;
+    }
+    Ok(dropped)
+}
+
+fn write_archive_code(
+    renderer: &mut Renderer<impl Read + Write>,
+    input: &str,
+    code: ArchiveCode,
+) -> Result<()> {
+    let digest = hex_digest(input);
+    let max_width = renderer.line_pixels_image();
+    let (image, caption) = match code {
+        ArchiveCode::Hash => (
+            qrcode_image(digest.as_bytes(), false, max_width)?,
+            format!("SHA-256 {}...", &digest[..16]),
+        ),
+        ArchiveCode::ShortHash => (
+            code128_image(&digest[..12], false, max_width)?,
+            digest[..12].to_string(),
+        ),
+        ArchiveCode::Content => (
+            qrcode_image(input.as_bytes(), false, max_width)
+                .context("archive code too large; try a hash instead")?,
+            "Full text".to_string(),
+        ),
+    };
+    renderer.write_image(&image)?;
+    renderer.set_format(renderer.format().with_justification(Justification::Center));
+    let result = renderer.write(&format!("{}\n", caption));
+    renderer.restore_format();
+    result
+}
+
+// Print a ruler for each font, so layouts can be checked against the
+// number of columns that actually fit
+fn write_rulers(renderer: &mut Renderer<impl Read + Write>) -> Result<()> {
+    for (name, format) in [
+        ("narrow", renderer.format()),
+        ("wide", renderer.format().without_flags(FormatFlags::NARROW)),
+    ] {
+        renderer.set_format(format);
+        let columns = renderer.line_columns();
+        let result = renderer.write(&format!("{} font, {} columns\n", name, columns));
+        let result = result.and_then(|_| renderer.write(&ruler(columns)));
+        renderer.restore_format();
+        result?;
+    }
+    renderer.write("\n")
+}
+
+// Two lines: column numbers every 5 columns, right-aligned over tick
+// marks.
+fn ruler(columns: usize) -> String {
+    let mut numbers = String::new();
+    for column in (5..=columns).step_by(5) {
+        let label = column.to_string();
+        numbers.push_str(&" ".repeat(column - label.len() - numbers.len()));
+        numbers.push_str(&label);
+    }
+    let ticks: String = (1..=columns)
+        .map(|column| if column % 5 == 0 { '|' } else { '.' })
+        .collect();
+    format!("{}\n{}\n", numbers, ticks)
+}
+
+/// Split out the contents of ```` ```section NAME ```` blocks whose names
+/// are in `routed`, for printing elsewhere.  Returns the rest of the
+/// document, with unrouted sections expanded in place, and the combined
+/// contents of each routed section in order of first appearance.
+pub fn split_sections(input: &str, routed: &[&str]) -> Result<(String, Vec<(String, String)>)> {
+    let mut main = String::new();
+    let mut sections: Vec<(String, String)> = Vec::new();
+    let mut copied = 0;
+    let mut current: Option<(String, String)> = None;
+    for (event, range) in Parser::new(input).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let mut it = info.split_whitespace();
+                if it.next() != Some("section") {
+                    continue;
+                }
+                let name = match (it.next(), it.next()) {
+                    (Some(name), None) => name,
+                    _ => bail!("section block needs exactly one name: {}", info),
+                };
+                main.push_str(&input[copied..range.start]);
+                copied = range.end;
+                current = Some((name.to_string(), String::new()));
+            }
+            Event::Text(contents) => {
+                if let Some((_, body)) = current.as_mut() {
+                    body.push_str(&contents);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((name, body)) = current.take() {
+                    if !routed.contains(&name.as_str()) {
+                        main.push_str(&body);
+                    } else if let Some((_, existing)) = sections.iter_mut().find(|s| s.0 == name) {
+                        existing.push_str(&body);
+                    } else {
+                        sections.push((name, body));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    main.push_str(&input[copied..]);
+    Ok((main, sections))
+}
+
+/// Parse CSV, or another delimiter-separated format such as TSV, into
+/// rows of fields.  Fields can be quoted with '"', with '""' for a
+/// literal quote; quoted fields can contain delimiters and newlines.
+/// Blank lines are skipped.
+pub fn parse_table(data: &str, delimiter: char) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut blank = true;
+    let mut line = 1;
+    let mut quote_line = 0;
+    let mut chars = data.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if quoted {
+            match ch {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = false,
+                _ => {
+                    if ch == '\n' {
+                        line += 1;
+                    }
+                    field.push(ch);
+                }
+            }
+            continue;
+        }
+        match ch {
+            '\r' if chars.peek() == Some(&'\n') => continue,
+            '\n' => {
+                line += 1;
+                if !blank {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                    blank = true;
+                }
+                continue;
+            }
+            '"' if field.is_empty() => {
+                quoted = true;
+                quote_line = line;
+            }
+            _ if ch == delimiter => row.push(std::mem::take(&mut field)),
+            _ => field.push(ch),
+        }
+        blank = false;
+    }
+    if quoted {
+        bail!("unterminated quoted field starting on line {}", quote_line);
+    }
+    if !blank {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Substitute `{{COLUMN}}` in the template with each column's value in
+/// the row.  Columns missing from the end of the row are empty.  Values
+/// are printed as written: Markdown punctuation is escaped, except in
+/// code, and placeholders in values aren't expanded.
+pub fn fill_template(template: &str, header: &[String], row: &[String]) -> String {
+    // Backslash escapes are printed literally in code, so values go
+    // there unchanged
+    let mut code = Vec::new();
+    let mut in_code_block = false;
+    for (event, range) in Parser::new_ext(template, parser_options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+            Event::Text(_) if in_code_block => code.push(range),
+            Event::Code(_) => code.push(range),
+            _ => {}
+        }
+    }
+
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let offset = template.len() - rest.len() + start;
+        let after = &rest[start + 2..];
+        let column = after.find("}}").and_then(|end| {
+            header
+                .iter()
+                .position(|name| *name == after[..end])
+                .map(|i| (i, end))
+        });
+        match column {
+            Some((i, end)) => {
+                let value = row.get(i).map(String::as_str).unwrap_or("");
+                if code.iter().any(|range| range.contains(&offset)) {
+                    output.push_str(value);
+                } else {
+                    for c in value.chars() {
+                        if c.is_ascii_punctuation() {
+                            output.push('\\');
+                        }
+                        output.push(c);
+                    }
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                output.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+// Decode an image from a base64 `data:` URI, dithered to black and
+// white.
+fn data_uri_image(url: &str) -> Result<StrikeImage> {
+    let (header, data) = url.split_once(',').context("invalid data URI")?;
+    if !header.ends_with(";base64") {
+        bail!("data URI isn't base64-encoded");
+    }
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .context("decoding base64")?;
+    let image = image::load_from_memory(&data)?.into_rgb8();
+    Ok(StrikeColors::new(false, 1).map_image(&image))
+}
+
+/// Check that the printer is ready without printing anything or moving
+/// the paper.
+pub fn healthcheck(device: &mut (impl Read + Write)) -> Result<()> {
+    status::Status::read(device)?.check()
+}
+
+// Identify the template and input in a line of small print, for tracing
+// a slip back to what produced it
+fn write_footer(
+    renderer: &mut Renderer<impl Read + Write>,
+    input: &str,
+    options: &RenderOptions,
+) -> Result<()> {
+    let mut parts = Vec::new();
+    if let Some(id) = &options.template_id {
+        parts.push(format!("Template {}", id));
+    }
+    if options.footer_hash {
+        parts.push(format!("Input {}", &hex_digest(input)[..12]));
+    }
+    if parts.is_empty() {
+        return Ok(());
+    }
+    renderer.set_format(Format::new().with_justification(Justification::Center));
+    let result = renderer.write(&format!("\n{}\n", parts.join("  ")));
+    renderer.restore_format();
+    result
+}
+
+fn hex_digest(input: &str) -> String {
+    let mut digest = String::new();
+    for byte in Sha256::digest(input) {
+        write!(digest, "{:02x}", byte).unwrap();
+    }
+    digest
+}
+
+// Call before anything is sent, so we can still back out
+fn check_max_lines(renderer: &Renderer<impl Read + Write>, options: &RenderOptions) -> Result<()> {
+    if let Some(max_lines) = options.max_lines {
+        if renderer.line_count() > max_lines {
+            bail!(
+                "refusing to print {} lines, more than the limit of {}",
+                renderer.line_count(),
+                max_lines
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_bytes(input: &str, options: &RenderOptions) -> Vec<u8> {
+        let mut output = std::io::Cursor::new(Vec::new());
+        render(input, options, &mut output).unwrap();
+        output.into_inner()
+    }
+
+    fn count_cuts(output: &[u8]) -> usize {
+        output.windows(4).filter(|w| w == b"\x1dV\x42\x50").count()
+    }
+
+    #[test]
+    fn lines_per_slip() {
+        let input = "one\n\ntwo\n\n```\nthree\nfour\nfive\n```\n\nsix\n";
+        assert_eq!(count_cuts(&render_bytes(input, &Default::default())), 1);
+        // each paragraph is two lines; the code block is deferred until
+        // its end
+        let options = RenderOptions {
+            lines_per_slip: NonZeroUsize::new(3),
+            ..Default::default()
+        };
+        assert_eq!(count_cuts(&render_bytes(input, &options)), 3);
+        // the last block fills the slip, so there's no blank slip after it
+        let options = RenderOptions {
+            lines_per_slip: NonZeroUsize::new(4),
+            ..Default::default()
+        };
+        assert_eq!(count_cuts(&render_bytes("one\n\ntwo\n", &options)), 1);
+    }
+
+    #[test]
+    fn split_on_heading() {
+        let input = "# Recipes\n\n## Soup\n\nStir.\n\n### Tips\n\n## Bread\n\nBake.\n";
+        assert_eq!(count_cuts(&render_bytes(input, &Default::default())), 1);
+        let split = |level| {
+            let options = RenderOptions {
+                split_on_heading: Some(level),
+                ..Default::default()
+            };
+            render_bytes(input, &options)
+        };
+        assert_eq!(count_cuts(&split(1)), 1);
+        assert_eq!(count_cuts(&split(2)), 3);
+        assert_eq!(count_cuts(&split(3)), 2);
+        // each cut comes right before its heading
+        let output = split(2);
+        let cut = output
+            .windows(4)
+            .position(|w| w == b"\x1dV\x42\x50")
+            .unwrap();
+        let soup = output.windows(4).position(|w| w == b"Soup").unwrap();
+        let recipes = output.windows(7).position(|w| w == b"Recipes").unwrap();
+        assert!(recipes < cut && cut < soup);
+
+        // no empty slip when the heading follows a rule
+        let options = RenderOptions {
+            split_on_heading: Some(1),
+            ..Default::default()
+        };
+        let output = render_bytes("foo\n\n---\n\n# Bar\n", &options);
+        assert_eq!(count_cuts(&output), 2);
+    }
+
+    #[test]
+    fn collapse_hard_breaks() {
+        let single = render_bytes("foo\\\nbar\n", &Default::default());
+        let input = "foo\\\n\\\n\\\nbar\n";
+        assert_ne!(render_bytes(input, &Default::default()), single);
+        let options = RenderOptions {
+            collapse_hard_breaks: true,
+            ..Default::default()
+        };
+        assert_eq!(render_bytes(input, &options), single);
+    }
+
+    #[test]
+    fn max_lines() {
+        let input = "one\n\ntwo\n";
+        let options = |max_lines| RenderOptions {
+            max_lines: Some(max_lines),
+            ..Default::default()
+        };
+        // each paragraph is two lines
+        assert_eq!(line_count(input, &Default::default()).unwrap(), 4);
+        render_bytes(input, &options(4));
+        let mut output = std::io::Cursor::new(Vec::new());
+        render(input, &options(3), &mut output).unwrap_err();
+        assert!(output.into_inner().is_empty());
+    }
+
+    #[test]
+    fn paper_out() {
+        use status::tests::MockPrinter;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static WAITS: AtomicUsize = AtomicUsize::new(0);
+        let ok = [0x12, 0x12, 0x12];
+        let out = [0x32, 0x12, 0x72];
+        // long enough to be sent in several chunks
+        let input = "word ".repeat(100);
+        let render_mock = |paper_out, responses: &[[u8; 3]]| {
+            let mut printer = MockPrinter {
+                responses: responses.iter().flatten().copied().collect(),
+                ..Default::default()
+            };
+            let options = RenderOptions {
+                paper_out,
+                on_paper_wait: Some(|| {
+                    WAITS.fetch_add(1, Ordering::SeqCst);
+                }),
+                ..Default::default()
+            };
+            let result = render(&input, &options, &mut printer);
+            (result, printer)
+        };
+        // Split the output into the chunks sent between status queries,
+        // with the number of queries after each
+        let query = b"\x10\x04\x02\x10\x04\x03\x10\x04\x04";
+        let split = |mut written: &[u8]| {
+            let mut chunks = Vec::new();
+            while !written.is_empty() {
+                let end = written
+                    .windows(query.len())
+                    .position(|w| w == query)
+                    .unwrap_or(written.len());
+                let chunk = written[..end].to_vec();
+                written = &written[end..];
+                let mut queries = 0;
+                while written.starts_with(query) {
+                    written = &written[query.len()..];
+                    queries += 1;
+                }
+                chunks.push((chunk, queries));
+            }
+            chunks
+        };
+
+        let (result, plain) = render_mock(None, &[]);
+        result.unwrap();
+        let document = plain.written;
+
+        // status is checked after each chunk of whole lines
+        let (result, printer) = render_mock(Some(PaperOut::Abort), &[ok; 100]);
+        result.unwrap();
+        let chunks = split(&printer.written);
+        assert!(chunks.len() > 2);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|(chunk, _)| chunk.as_slice())
+                .collect::<Vec<_>>()
+                .concat(),
+            document
+        );
+        for (chunk, queries) in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= 256);
+            assert!(chunk.ends_with(b"\r\n"));
+            assert_eq!(*queries, 1);
+        }
+
+        let (result, printer) = render_mock(Some(PaperOut::Abort), &[ok, out]);
+        let err = result.unwrap_err();
+        let sent = chunks[0].0.len() + chunks[1].0.len();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "printer ran out of paper after {} of {} bytes were sent",
+                sent,
+                document.len()
+            )
+        );
+        assert_eq!(printer.written.len(), sent + 2 * query.len());
+        assert_eq!(WAITS.load(Ordering::SeqCst), 0);
+
+        // waiting resumes where it stopped
+        let mut responses = vec![ok, out, out, ok];
+        responses.resize(chunks.len() + 2, ok);
+        let (result, printer) = render_mock(Some(PaperOut::Wait), &responses);
+        result.unwrap();
+        assert!(printer.responses.is_empty());
+        let waited = split(&printer.written);
+        assert_eq!(waited.len(), chunks.len());
+        for (i, ((chunk, queries), (expected, _))) in waited.iter().zip(&chunks).enumerate() {
+            assert_eq!(chunk, expected);
+            // the second chunk is followed by polling until paper is back
+            assert_eq!(*queries, if i == 1 { 3 } else { 1 });
+        }
+        assert_eq!(WAITS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn warn_low_paper() {
+        use status::tests::MockPrinter;
+
+        let render_mock = |near_end: u8, note| {
+            let mut printer = MockPrinter {
+                responses: [0x12, 0x12, 0x12 | near_end].into(),
+                ..Default::default()
+            };
+            let options = RenderOptions {
+                warn_low_paper: true,
+                low_paper_note: note,
+                ..Default::default()
+            };
+            let report = render("foo\n", &options, &mut printer).unwrap();
+            (report.low_paper, printer.written)
+        };
+        let (low, output) = render_mock(0, true);
+        assert!(!low);
+        assert!(!contains(&output, b"Paper is running low"));
+        let (low, output) = render_mock(0x0c, false);
+        assert!(low);
+        assert!(!contains(&output, b"Paper is running low"));
+        let (low, output) = render_mock(0x0c, true);
+        assert!(low);
+        // the note is printed before the final cut
+        let note = output
+            .windows(20)
+            .position(|w| w == b"Paper is running low")
+            .unwrap();
+        assert!(note < output.len() - 4);
+        assert!(output.ends_with(b"\x1dV\x42\x50"));
+        // the sensor is checked before anything is sent
+        assert!(output.starts_with(b"\x10\x04\x02\x10\x04\x03\x10\x04\x04\x1b@"));
+
+        // the note counts against the line limit, and nothing is printed
+        // if it doesn't fit
+        let mut printer = MockPrinter {
+            responses: [0x12, 0x12, 0x1e].into(),
+            ..Default::default()
+        };
+        let options = RenderOptions {
+            warn_low_paper: true,
+            low_paper_note: true,
+            max_lines: Some(3),
+            ..Default::default()
+        };
+        render("foo\n", &options, &mut printer).unwrap_err();
+        assert_eq!(printer.written, b"\x10\x04\x02\x10\x04\x03\x10\x04\x04");
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn qr_link() {
+        let plain = render_bytes("foo\n", &Default::default());
+        let options = RenderOptions {
+            qr_link: Some("https://example.com/".into()),
+            qr_caption: "Scan me".into(),
+            ..Default::default()
+        };
+        let output = render_bytes("foo\n", &options);
+        let find = |needle: &[u8]| output.windows(needle.len()).position(|w| w == needle);
+        let text = find(b"foo").unwrap();
+        let image = find(b"\x1b*").unwrap();
+        let caption = find(b"Scan me").unwrap();
+        assert!(text < image && image < caption);
+        assert!(!contains(&plain, b"\x1b*"));
+    }
+
+    #[test]
+    fn archive_code() {
+        let output = |code| {
+            let options = RenderOptions {
+                archive_code: Some(code),
+                ..Default::default()
+            };
+            render_bytes("foo\n", &options)
+        };
+        // sha256("foo\n")
+        let digest = b"b5bb9d8014a0f9b1d61e21e796d78dcc";
+        let hash = output(ArchiveCode::Hash);
+        assert!(contains(&hash, b"\x1b*"));
+        assert!(contains(
+            &hash,
+            &[b"SHA-256 ", &digest[..16], b"..."].concat()
+        ));
+        let short = output(ArchiveCode::ShortHash);
+        assert!(contains(&short, b"\x1b*"));
+        assert!(contains(&short, &digest[..12]));
+        let content = output(ArchiveCode::Content);
+        assert!(contains(&content, b"Full text"));
+
+        // too large for a QR code
+        let options = RenderOptions {
+            archive_code: Some(ArchiveCode::Content),
+            ..Default::default()
+        };
+        let mut out = std::io::Cursor::new(Vec::new());
+        render(&"foo ".repeat(250), &options, &mut out).unwrap_err();
+
+        assert_eq!(
+            "short-hash".parse::<ArchiveCode>().unwrap(),
+            ArchiveCode::ShortHash
+        );
+        "md5".parse::<ArchiveCode>().unwrap_err();
+    }
+
+    #[test]
+    fn dropped_content() {
+        let render_report = |input: &str| {
+            render(
+                input,
+                &Default::default(),
+                &mut std::io::Cursor::new(Vec::new()),
+            )
+            .unwrap()
+        };
+        let report = render_report("# Plain\n\n*text* and `code`\n");
+        assert!(report.dropped.is_empty());
+        assert_eq!(report.dropped.to_string(), "");
+
+        let report = render_report(concat!(
+            "<div>\nblock\n</div>\n\n",
+            "See <b>the</b> [docs](https://example.com) and [more](https://example.org).\n\n",
+            "![logo](logo.png) caf\u{e9} \u{2192}\n",
+            "![](data:image/x-portable-bitmap;base64,UDEgMSAxIDE=)\n",
+        ));
+        assert_eq!(
+            report.dropped,
+            DroppedContent {
+                html: 3,
+                link_urls: 2,
+                images: 1,
+                replaced_chars: 2,
+            }
+        );
+        assert_eq!(
+            report.dropped.to_string(),
+            "3 raw HTML fragment(s) skipped, 2 link URL(s) not printed, \
+             1 image(s) printed as alt text, 2 unprintable character(s) replaced"
+        );
+
+        let mut total = DroppedContent::default();
+        total.merge(&report.dropped);
+        total.merge(&report.dropped);
+        assert_eq!(total.link_urls, 4);
+        assert_eq!(total.replaced_chars, 4);
+    }
+
+    #[test]
+    fn table_data() {
+        let table = |data: &str| parse_table(data, ',').unwrap();
+        assert_eq!(
+            table("name,qty\nWidget,3\n"),
+            [vec!["name", "qty"], vec!["Widget", "3"]]
+        );
+        // CRLF, blank lines, empty fields, no final newline
+        assert_eq!(
+            table("a,b\r\n\r\n,x\r\n\n1,\r\n2"),
+            [vec!["a", "b"], vec!["", "x"], vec!["1", ""], vec!["2"]]
+        );
+        // quoting
+        assert_eq!(
+            table("\"a, b\",\"say \"\"hi\"\"\"\n\"two\nlines\",x\"y\n"),
+            [vec!["a, b", "say \"hi\""], vec!["two\nlines", "x\"y"]]
+        );
+        assert_eq!(table("\"\"\n"), [vec![""]]);
+        assert_eq!(table(""), Vec::<Vec<String>>::new());
+        assert_eq!(parse_table("a,b\tc\n", '\t').unwrap(), [vec!["a,b", "c"]]);
+        let err = parse_table("a\nb,\"c\n", ',').unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated quoted field starting on line 2"
+        );
+
+        let header = table("name,qty,note")[0].clone();
+        assert_eq!(
+            fill_template(
+                "{{name}} x{{qty}} {{note}}{{other}}",
+                &header,
+                &["Widget".into(), "3".into()]
+            ),
+            "Widget x3 {{other}}"
+        );
+        // values are escaped outside code, and not expanded again
+        assert_eq!(
+            fill_template(
+                "# {{name}}\n\n`{{qty}}`\n\n```code128\n{{qty}}\n```\n{{note}}\n",
+                &header,
+                &["*Big* {{qty}}".into(), "1-2_3".into(), "1. x".into()]
+            ),
+            "# \\*Big\\* \\{\\{qty\\}\\}\n\n`1-2_3`\n\n```code128\n1-2_3\n```\n1\\. x\n"
+        );
+        let rendered = render_bytes(
+            &fill_template("{{name}}\n", &header, &["*Big* [link](x)".into()]),
+            &Default::default(),
+        );
+        assert!(contains(&rendered, b"*Big* [link](x)\r"));
+    }
+
+    #[test]
+    fn footer() {
+        let plain = render_bytes("foo\n", &Default::default());
+        assert!(!contains(&plain, b"Template"));
+        let options = RenderOptions {
+            template_id: Some("receipt-v3".into()),
+            ..Default::default()
+        };
+        let output = render_bytes("foo\n", &options);
+        assert!(contains(&output, b"Template receipt-v3\r"));
+        assert!(!contains(&output, b"Input"));
+        let options = RenderOptions {
+            template_id: Some("receipt-v3".into()),
+            footer_hash: true,
+            ..Default::default()
+        };
+        let output = render_bytes("foo\n", &options);
+        assert!(contains(
+            &output,
+            b"Template receipt-v3  Input b5bb9d8014a0\r"
+        ));
+        // after the document, before the final cut
+        let footer = output.windows(8).position(|w| w == b"Template").unwrap();
+        assert!(output.windows(3).position(|w| w == b"foo").unwrap() < footer);
+        assert!(output.ends_with(b"\x1dV\x42\x50"));
+    }
+
+    #[test]
+    fn darkness() {
+        let output = |darkness| {
+            let options = RenderOptions {
+                darkness: Some(darkness),
+                ..Default::default()
+            };
+            render_bytes("foo\n", &options)
+        };
+        // narrow font, optionally emphasized
+        let normal = output(1);
+        assert!(contains(&normal, b"\x1b!\x01"));
+        assert!(!contains(&normal, b"\x1b!\x09"));
+        assert!(!contains(&normal, b"\x1bU\x01"));
+        assert_eq!(render_bytes("foo\n", &Default::default()), normal);
+        let double = output(2);
+        assert!(!contains(&double, b"\x1b!\x01"));
+        assert!(contains(&double, b"\x1b!\x09"));
+        assert!(!contains(&double, b"\x1bU\x01"));
+        let unidirectional = output(3);
+        assert!(contains(&unidirectional, b"\x1b!\x09"));
+        assert!(contains(&unidirectional, b"\x1bU\x01"));
+        assert!(!contains(&unidirectional, b"\x1bU\x00"));
+
+        let mut output = std::io::Cursor::new(Vec::new());
+        let options = RenderOptions {
+            darkness: Some(4),
+            ..Default::default()
+        };
+        render("foo\n", &options, &mut output).unwrap_err();
+    }
+
+    #[test]
+    fn sections() {
+        let input = "# Order 12\n\n```section kitchen\nBurger\n```\n\n````section bar\n- Soda\n```\nice\n```\n````\n\n```section kitchen\nFries\n```\n\n```text\nsection\n```\nEnd\n";
+        let (main, sections) = split_sections(input, &["kitchen", "bar"]).unwrap();
+        assert_eq!(
+            main,
+            "# Order 12\n\n\n\n\n\n\n\n```text\nsection\n```\nEnd\n"
+        );
+        assert_eq!(
+            sections,
+            [
+                ("kitchen".into(), "Burger\nFries\n".into()),
+                ("bar".into(), "- Soda\n```\nice\n```\n".into())
+            ]
+        );
+
+        // unrouted sections stay in place
+        let (main, sections) = split_sections(input, &["bar"]).unwrap();
+        assert_eq!(
+            main,
+            "# Order 12\n\nBurger\n\n\n\n\nFries\n\n\n```text\nsection\n```\nEnd\n"
+        );
+        assert_eq!(sections.len(), 1);
+        let (main, sections) = split_sections(input, &[]).unwrap();
+        assert!(main.contains("- Soda\n```\nice\n```\n"));
+        assert!(sections.is_empty());
+
+        split_sections("```section\nfoo\n```\n", &[]).unwrap_err();
+        split_sections("```section a b\nfoo\n```\n", &[]).unwrap_err();
+    }
+
+    #[test]
+    fn table() {
+        let input = concat!(
+            "| Qty | Item | Price |\n",
+            "|--:|---|--:|\n",
+            "| 2 | *Burger* | $12.00 |\n",
+            "| 10 | `Fries` |\n",
+            "\n",
+            "after\n",
+        );
+        let output = render_bytes(input, &Default::default());
+        let preview = super::preview(input, &Default::default(), PreviewStyle::Plain).unwrap();
+        assert!(
+            preview.starts_with(concat!(
+                "Qty  Item     Price\n",
+                "---  ------  ------\n",
+                "  2  Burger  $12.00\n",
+                " 10  Fries\n",
+            )),
+            "{}",
+            preview
+        );
+        // the header is emphasized
+        let header = output.windows(3).position(|w| w == b"Qty").unwrap();
+        assert!(contains(&output[..header], b"\x1b!\x09"));
+        assert!(contains(&output, b"after"));
+
+        // inline formatting is kept
+        let preview = super::preview(input, &Default::default(), PreviewStyle::Ansi).unwrap();
+        assert!(preview.contains("\x1b[0;4mBurger"), "{:?}", preview);
+        assert!(preview.contains("\x1b[0;31mFries"), "{:?}", preview);
+
+        // long cells wrap within their column
+        let long = "word ".repeat(12);
+        let input = format!("| a | b |\n|---|---|\n| x | {} |\n", long);
+        let output = render_bytes(&input, &Default::default());
+        let width = 40 - 1 - 2;
+        let line = format!("x  {}\r", "word ".repeat(width / 5).trim_end());
+        assert!(contains(&output, line.as_bytes()), "{}", line);
+        let line = format!("   {}\r", "word ".repeat(12 - width / 5).trim_end());
+        assert!(contains(&output, line.as_bytes()), "{}", line);
+
+        // too many columns to align falls back to one line per row
+        let input = format!("{0}\n{1}\n{0}\n", "|x".repeat(15), "|-".repeat(15));
+        let output = super::preview(&input, &Default::default(), PreviewStyle::Plain).unwrap();
+        let row = format!("{}\nx\n", vec!["x"; 14].join("  "));
+        assert!(output.starts_with(&row.repeat(2)), "{}", output);
+
+        // a table block can print rows as lists when columns would get
+        // too narrow
+        let input = concat!(
+            "```table overflow=list minwidth=35\n",
+            "| Item | Description |\n",
+            "|---|---|\n",
+            "| Burger | A very long description of the burger |\n",
+            "| Fries | *Crispy* |\n",
+            "```\n",
+            "after\n",
+        );
+        let output = super::preview(input, &Default::default(), PreviewStyle::Plain).unwrap();
+        assert!(
+            output.starts_with(concat!(
+                "Item: Burger\n",
+                "Description: A very long description of\n",
+                "the burger\n",
+                "\n",
+                "Item: Fries\n",
+                "Description: Crispy\n",
+                "\n",
+                "after\n",
+            )),
+            "{}",
+            output
+        );
+        // without the minimum, the columns are narrowed to fit
+        let output = super::preview(
+            &input.replace(" minwidth=35", ""),
+            &Default::default(),
+            PreviewStyle::Plain,
+        )
+        .unwrap();
+        assert!(output.starts_with("Item    Description\n"), "{}", output);
+
+        // shading every other row
+        let shade = [b"\x1b*\x00\x05\x00".as_slice(), &[0, 0x22, 0, 0x88, 0]].concat();
+        let count_shaded = |input: &str| {
+            render_bytes(input, &Default::default())
+                .windows(shade.len())
+                .filter(|w| *w == shade.as_slice())
+                .count()
+        };
+        let input = "| a |\n|---|\n| 1 |\n| 2 |\n| 3 |\n| 4 |\n";
+        assert_eq!(count_shaded(input), 0);
+        assert_eq!(count_shaded(&format!("```table shade\n{}```\n", input)), 2);
+
+        // width hints
+        let input = "```table widths=4,*\n| a | b |\n|---|---|\n| 123456 | x |\n```\n";
+        let output = super::preview(input, &Default::default(), PreviewStyle::Plain).unwrap();
+        assert!(
+            output.starts_with("a     b\n----  -\n1234  x\n56\n"),
+            "{}",
+            output
+        );
+        let mut output = std::io::Cursor::new(Vec::new());
+        render(
+            &input.replace("widths=4,*", "widths=38,*"),
+            &Default::default(),
+            &mut output,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn table_char_width() {
+        // inline formatting in cells doesn't change character width, so
+        // columns counted in characters line up
+        let input = concat!(
+            "> | A | B |\n",
+            "> |---|--:|\n",
+            "> | **bold** | *italic* |\n",
+            "> | `code` | ~~struck~~ |\n",
+            "> | [link](https://example.com/) | ![alt](x.png) |\n",
+        );
+        let output = render_bytes(input, &Default::default());
+        let modes: Vec<u8> = output
+            .windows(3)
+            .filter(|w| w[..2] == *b"\x1b!")
+            .map(|w| w[2] & (FormatFlags::NARROW | FormatFlags::DOUBLE_WIDTH).bits())
+            .collect();
+        assert!(modes.len() > 1);
+        assert!(modes.iter().all(|mode| *mode == modes[0]), "{:?}", modes);
+        let preview = super::preview(input, &Default::default(), PreviewStyle::Plain).unwrap();
+        assert!(
+            preview.starts_with(concat!(
+                "    A          B\n",
+                "    ----  ------\n",
+                "    bold  italic\n",
+                "    code  struck\n",
+                "    link     alt\n",
+            )),
+            "{}",
+            preview
+        );
+    }
+
+    #[test]
+    fn wrap_marker_menu() {
+        // full-width lines leave room for the marker instead of wrapping
+        let options = RenderOptions {
+            wrap_marker: Some('\\'),
+            ..Default::default()
+        };
+        let input = "```menu\nBurger | $5.00\n```\n";
+        let output = super::preview(input, &options, PreviewStyle::Plain).unwrap();
+        let line = output.lines().next().unwrap();
+        assert_eq!(line, format!("Burger {} $5.00", ".".repeat(26)));
+    }
+
+    #[test]
+    fn preview() {
+        let input = "# Hi\n\nsome `code` and ~~not~~ this\n\n---\n\nafter\n";
+        let output = super::preview(input, &Default::default(), PreviewStyle::Plain).unwrap();
+        let cut = format!("{0} cut {0}-\n", "-".repeat(17));
+        assert_eq!(
+            output,
+            format!(
+                "{}H i\n\nsome code and not this\n\n{1}after\n\n{1}",
+                " ".repeat(18),
+                cut
+            )
+        );
+
+        // passes are merged into one line
+        let output = super::preview("a `b` c\n", &Default::default(), PreviewStyle::Ansi).unwrap();
+        assert!(
+            output.starts_with("a \x1b[0;31mb\x1b[0m c\n"),
+            "{:?}",
+            output
+        );
+
+        // images are drawn with block characters
+        let options = RenderOptions {
+            qr_link: Some("https://example.com/".into()),
+            ..Default::default()
+        };
+        let output = super::preview("", &options, PreviewStyle::Plain).unwrap();
+        assert!(output.contains('█'));
+        assert!(output.lines().all(|line| line.chars().count() <= 40));
+
+        let options = RenderOptions {
+            check_status: true,
+            ..Default::default()
+        };
+        super::preview("", &options, PreviewStyle::Plain).unwrap_err();
+    }
+
+    #[test]
+    fn inline_image() {
+        let icon = "![icon](data:image/x-portable-bitmap;base64,UDEgMyAyIDEgMCAxIDAgMSAw)";
+        let output = render_bytes(&format!("a {} b\n", icon), &Default::default());
+        // one narrow character cell, in the same line as the text
+        let cell = b"\x1b*\x00\x05\x00\x80\x40\x80\x00\x00";
+        let line = [b"a ".as_slice(), cell, b" b\r"].concat();
+        assert!(contains(&output, &line));
+        assert!(!contains(&output, b"icon"));
+
+        // red text leaves a blank cell in the black pass
+        let output = render_bytes(&format!("`a` {}\n", icon), &Default::default());
+        assert!(contains(&output, &[cell.as_slice(), b"\r"].concat()));
+        assert!(contains(&output, b"a\x1b"));
+
+        // other images still print their alt text
+        let output = render_bytes("![icon](icon.png)\n", &Default::default());
+        assert!(contains(&output, b"icon"));
+
+        let mut output = std::io::Cursor::new(Vec::new());
+        let tall = "![](data:image/x-portable-bitmap;base64,UDEgMSA5IDEgMSAxIDEgMSAxIDEgMSAx)\n";
+        render(tall, &Default::default(), &mut output).unwrap_err();
+        render(
+            "![](data:text/plain,foo)\n",
+            &Default::default(),
+            &mut output,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn healthcheck() {
+        use status::tests::MockPrinter;
+
+        let mut printer = MockPrinter {
+            responses: [0x12, 0x12, 0x12 | 0x0c].into(),
+            ..Default::default()
+        };
+        super::healthcheck(&mut printer).unwrap();
+        // only status queries were sent
+        assert_eq!(printer.written, b"\x10\x04\x02\x10\x04\x03\x10\x04\x04");
+
+        let mut printer = MockPrinter {
+            responses: [0x12 | 0x04, 0x12, 0x12].into(),
+            ..Default::default()
+        };
+        let err = super::healthcheck(&mut printer).unwrap_err();
+        assert!(err.to_string().contains("cover open"), "{}", err);
+
+        // no response
+        super::healthcheck(&mut MockPrinter::default()).unwrap_err();
+    }
+
+    #[test]
+    fn escapes() {
+        let plain = render_bytes("plain\n", &Default::default());
+        for (input, expected) in [
+            ("\\# heading\n", &b"# heading"[..]),
+            ("\\*emphasis\\*\n", b"*emphasis*"),
+            ("\\`code\\`\n", b"`code`"),
+            ("\\\\\n", b"\\"),
+        ] {
+            let output = render_bytes(input, &Default::default());
+            assert!(
+                contains(&output, expected),
+                "{:?} rendered as {:?}",
+                input,
+                String::from_utf8_lossy(&output)
+            );
+            // the escaping backslash is gone
+            assert!(
+                !contains(&output, &[b"\\", &expected[..1]].concat()),
+                "{:?}",
+                input
+            );
+            // no formatting commands beyond those for plain text
+            let commands = |output: &[u8]| output.iter().filter(|b| **b == 0x1b).count();
+            assert_eq!(commands(&output), commands(&plain), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn ruler() {
+        assert_eq!(super::ruler(12), "    5   10\n....|....|..\n");
+        let options = RenderOptions {
+            ruler: true,
+            ..Default::default()
+        };
+        let output = render_bytes("foo\n", &options);
+        assert!(contains(&output, b"narrow font, 40 columns"));
+        assert!(contains(&output, "....|".repeat(8).as_bytes()));
+        assert!(contains(&output, b"wide font, 32 columns"));
+        let wide = format!("{}..\r", "....|".repeat(6));
+        assert!(contains(&output, wide.as_bytes()));
+        let options = RenderOptions {
+            ruler: true,
+            left_margin: 80,
+            ..Default::default()
+        };
+        assert!(contains(
+            &render_bytes("foo\n", &options),
+            b"narrow font, 30 columns"
+        ));
+    }
+
+    #[test]
+    fn rtl() {
+        // non-ASCII characters print as '?', so check the order of the
+        // neutral characters inside the RTL run
+        let input = "abc \u{05d0}, \u{05d1}\n";
+        let output = render_bytes(input, &Default::default());
+        assert!(contains(&output, b"abc ?, ?"));
+        assert!(!contains(&output, b"\x1ba\x02"));
+        let options = RenderOptions {
+            rtl: true,
+            ..Default::default()
+        };
+        let output = render_bytes(input, &options);
+        assert!(contains(&output, b"abc ? ,?"));
+        assert!(contains(&output, b"\x1ba\x02"));
+    }
+}
@@ -14,20 +14,26 @@
  * limitations under the License.
 */
 
-mod codeblock;
-mod render;
-mod strike;
-
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser as ClapParser;
 use fs2::FileExt;
-use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag};
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use mintmark::{
+    fill_template, healthcheck, line_count, parse_table, preview, render, split_sections,
+    ArchiveCode, FontMetrics, PaperOut, PreviewStyle, RenderOptions, RenderReport,
+};
 
-use codeblock::CodeBlockConfig;
-use render::{FormatFlags, Justification, Renderer};
+// How long --healthcheck waits for the printer to answer
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Print Markdown to an Epson TM-U220B receipt printer
 #[derive(Debug, ClapParser)]
@@ -39,13 +45,202 @@ struct Args {
     /// Lock file for coordinating exclusive access
     #[arg(long, value_name = "PATH")]
     lock_file: Option<PathBuf>,
+    /// Give up if the lock file can't be locked within SECONDS (default:
+    /// wait forever)
+    #[arg(long, value_name = "SECONDS", requires = "lock_file", value_parser = parse_timeout)]
+    lock_timeout: Option<Duration>,
+    /// Sound the buzzer N times after printing
+    #[arg(long, value_name = "N")]
+    beep: Option<u8>,
+    /// Cut the paper after roughly every N printed lines
+    #[arg(long, value_name = "N")]
+    lines_per_slip: Option<NonZeroUsize>,
+    /// Reverse right-to-left text and right-align its lines
+    #[arg(long)]
+    rtl: bool,
+    /// Print consecutive hard line breaks as a single blank line
+    #[arg(long)]
+    collapse_hard_breaks: bool,
+    /// Left margin in half-dots (1/160 inch)
+    #[arg(long, value_name = "N", default_value = "0")]
+    left_margin: u16,
+    /// Print a QR code linking to URL at the end of the document
+    #[arg(long, value_name = "URL")]
+    qr_link: Option<String>,
+    /// Caption for the --qr-link code, or "" for none
+    #[arg(
+        long,
+        value_name = "TEXT",
+        requires = "qr_link",
+        default_value = "Scan for the full document"
+    )]
+    qr_caption: String,
+    /// Print a barcode identifying the document at the end [hash,
+    /// short-hash, content]
+    #[arg(long, value_name = "TYPE")]
+    archive_code: Option<ArchiveCode>,
+    /// Print darkness: 1 is normal, 2 double-strikes, 3 also prints
+    /// unidirectionally
+    #[arg(long, value_name = "1-3")]
+    darkness: Option<u8>,
+    /// Cut the paper before each heading of level N (1-6), printing each
+    /// section as a separate slip
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=6))]
+    split_on_heading: Option<u8>,
+    /// Print column rulers for the narrow and wide fonts before the
+    /// document
+    #[arg(long)]
+    ruler: bool,
+    /// Characters after which long words can wrap, in addition to spaces,
+    /// e.g. "-/"
+    #[arg(long, value_name = "CHARS", default_value = "")]
+    break_chars: String,
+    /// Character pitch of the narrow and wide fonts in half-dots, for
+    /// printers whose fonts differ from the TM-U220B's [advanced]
+    #[arg(long, value_name = "NARROW,WIDE", value_parser = parse_pair, default_value = "8,10")]
+    char_width: (usize, usize),
+    /// Width of strikethrough and shading per narrow and wide character,
+    /// in bit image dots [advanced]
+    #[arg(long, value_name = "NARROW,WIDE", value_parser = parse_pair, default_value = "5,6")]
+    overstrike_width: (usize, usize),
+    /// Print CHAR at the end of each soft-wrapped line, e.g. '\'
+    #[arg(long, value_name = "CHAR")]
+    wrap_marker: Option<char>,
+    /// Indent the continuation of soft-wrapped lines by N more characters
+    #[arg(long, value_name = "N", default_value = "0")]
+    wrap_indent: usize,
+    /// Print ID in a footer identifying the template that produced the
+    /// slip
+    #[arg(long, value_name = "ID")]
+    template_id: Option<String>,
+    /// Print the start of the input's SHA-256 digest in the footer
+    #[arg(long)]
+    footer_hash: bool,
+    /// Allow image blocks to load layer files from DIR (default: no files
+    /// can be loaded)
+    #[arg(long, value_name = "DIR")]
+    image_dir: Option<PathBuf>,
+    /// Replace each occurrence of KEY in the input with VALUE [repeatable]
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    replace: Vec<(String, String)>,
+    /// Print the document once per row of the CSV file at PATH (TSV if
+    /// it ends in .tsv), replacing {{COLUMN}} with the row's values
+    #[arg(long, value_name = "PATH")]
+    data: Option<PathBuf>,
+    /// Print "section NAME" blocks to the device at PATH instead of the
+    /// main device [repeatable]
+    #[arg(long, value_name = "NAME=PATH", value_parser = parse_key_value)]
+    route: Vec<(String, String)>,
+    /// Refuse to print documents longer than N lines
+    #[arg(long, value_name = "N", default_value = "1000")]
+    max_lines: usize,
+    /// Print even if the document is longer than --max-lines
+    #[arg(long)]
+    force: bool,
+    /// Exit with an error if the printer reports a problem once the
+    /// document is sent
+    #[arg(long, conflicts_with = "socket")]
+    check_status: bool,
+    /// If the paper runs out while printing, wait for more to be loaded
+    /// or abort
+    #[arg(long, value_name = "wait|abort", conflicts_with = "socket")]
+    paper_out: Option<PaperOut>,
+    /// Warn if the printer's paper is nearly out before printing
+    #[arg(long, conflicts_with = "socket")]
+    warn_low_paper: bool,
+    /// Also print a note on the slip if the paper is nearly out
+    #[arg(long, requires = "warn_low_paper")]
+    low_paper_note: bool,
+    /// Report input that was dropped or printed in degraded form
+    #[arg(long)]
+    report_dropped: bool,
+    /// Check that the printer is reachable and ready, then exit without
+    /// printing
+    #[arg(long, conflicts_with_all = ["file", "replace", "data"])]
+    healthcheck: bool,
+    /// Write a text approximation of the printout to stdout instead of
+    /// printing, with formatting shown as ANSI escapes or omitted
+    #[arg(
+        long,
+        value_name = "ansi|plain",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "ansi",
+        conflicts_with_all = [
+            "device", "socket", "check_status", "paper_out", "warn_low_paper", "report_dropped",
+            "healthcheck"
+        ]
+    )]
+    preview: Option<PreviewStyle>,
+    /// Unix socket of a print daemon to send output to
+    #[arg(long, value_name = "PATH", conflicts_with = "device")]
+    socket: Option<PathBuf>,
     /// Path to the character device node
-    #[arg(value_name = "DEVICE-PATH")]
-    device: PathBuf,
+    #[arg(
+        value_name = "DEVICE-PATH",
+        required_unless_present_any = ["socket", "preview"]
+    )]
+    device: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    if args.healthcheck {
+        // Don't wait behind a print job; report that the printer is busy
+        let _lockfile = lock(args.lock_file, Some(Duration::ZERO))?;
+        // The daemon owns the printer, so a successful connection is the
+        // best we can check
+        if let Some(path) = args.socket {
+            UnixStream::connect(path).context("connecting to socket")?;
+        } else {
+            // A wedged printer may never answer, and the read can't be
+            // interrupted, so leave it behind on a thread
+            let mut device = open_device(args.device)?;
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || sender.send(healthcheck(&mut device)));
+            receiver.recv_timeout(HEALTHCHECK_TIMEOUT).map_err(|_| {
+                anyhow::anyhow!(
+                    "printer didn't respond within {} seconds",
+                    HEALTHCHECK_TIMEOUT.as_secs()
+                )
+            })??;
+        }
+        return Ok(());
+    }
+
+    let options = RenderOptions {
+        beep: args.beep,
+        lines_per_slip: args.lines_per_slip,
+        rtl: args.rtl,
+        collapse_hard_breaks: args.collapse_hard_breaks,
+        left_margin: args.left_margin,
+        break_chars: args.break_chars,
+        darkness: args.darkness,
+        qr_link: args.qr_link,
+        qr_caption: args.qr_caption,
+        archive_code: args.archive_code,
+        check_status: args.check_status,
+        max_lines: (!args.force).then_some(args.max_lines),
+        warn_low_paper: args.warn_low_paper,
+        low_paper_note: args.low_paper_note,
+        ruler: args.ruler,
+        font_metrics: FontMetrics {
+            narrow_width: args.char_width.0,
+            wide_width: args.char_width.1,
+            narrow_overstrike: args.overstrike_width.0,
+            wide_overstrike: args.overstrike_width.1,
+        },
+        split_on_heading: args.split_on_heading,
+        wrap_marker: args.wrap_marker,
+        wrap_indent: args.wrap_indent,
+        template_id: args.template_id,
+        footer_hash: args.footer_hash,
+        paper_out: args.paper_out,
+        on_paper_wait: Some(|| {
+            eprintln!("Printer is out of paper; waiting for paper to be loaded")
+        }),
+        image_dir: args.image_dir,
+    };
 
     let mut input_bytes: Vec<u8> = Vec::new();
     match args.file {
@@ -60,224 +255,230 @@ fn main() -> Result<()> {
             .read_to_end(&mut input_bytes)
             .context("reading stdin")?,
     };
-    let input = std::str::from_utf8(&input_bytes).context("couldn't decode input")?;
-
-    let _lockfile = args
-        .lock_file
-        .map(|path| -> Result<File> {
-            let file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(path)
-                .context("opening lockfile")?;
-            file.lock_exclusive().context("locking lockfile")?;
-            Ok(file)
-        })
-        .transpose()?;
-    let mut output = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(args.device)
-        .context("opening output")?;
+    let mut input = decode_input(&input_bytes)?.to_string();
+    for (key, value) in &args.replace {
+        input = input.replace(key, value);
+    }
 
-    render(input, &mut output)
-}
+    let documents = match &args.data {
+        Some(path) => fill_data(&input, path)?,
+        None => vec![input],
+    };
 
-fn render(input: &str, output: &mut (impl Read + Write)) -> Result<()> {
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    let parser = Parser::new_ext(input, options);
-
-    let mut renderer = Renderer::new(output);
-    let mut code_block: Option<CodeBlockConfig> = None;
-    let mut lists: Vec<Option<u64>> = Vec::new();
-    for (event, _) in parser.into_offset_iter() {
-        match event {
-            Event::Start(tag) => {
-                match tag {
-                    Tag::Paragraph => {}
-                    Tag::Heading(level, _, _) => {
-                        // Center first.  This only takes effect at the
-                        // start of the line, so end tag handling needs to
-                        // specially account for it.
-                        renderer.set_format(
-                            renderer.format().with_justification(Justification::Center),
-                        );
-                        match level {
-                            HeadingLevel::H1 => {
-                                renderer.set_format(
-                                    renderer.format().with_unidirectional(true).with_flags(
-                                        FormatFlags::DOUBLE_HEIGHT
-                                            | FormatFlags::DOUBLE_WIDTH
-                                            | FormatFlags::EMPHASIZED
-                                            | FormatFlags::UNDERLINE,
-                                    ),
-                                );
-                            }
-                            HeadingLevel::H2 => {
-                                renderer.set_format(
-                                    renderer.format().with_unidirectional(true).with_flags(
-                                        FormatFlags::DOUBLE_HEIGHT
-                                            | FormatFlags::DOUBLE_WIDTH
-                                            | FormatFlags::EMPHASIZED,
-                                    ),
-                                );
-                            }
-                            HeadingLevel::H3 => {
-                                renderer.set_format(
-                                    renderer
-                                        .format()
-                                        .with_flags(
-                                            FormatFlags::EMPHASIZED | FormatFlags::UNDERLINE,
-                                        )
-                                        .without_flags(FormatFlags::NARROW),
-                                );
-                            }
-                            HeadingLevel::H4 => {
-                                renderer.set_format(
-                                    renderer
-                                        .format()
-                                        .with_flags(FormatFlags::EMPHASIZED)
-                                        .without_flags(FormatFlags::NARROW),
-                                );
-                            }
-                            HeadingLevel::H5 => {
-                                renderer.set_format(
-                                    renderer.format().with_flags(
-                                        FormatFlags::EMPHASIZED | FormatFlags::UNDERLINE,
-                                    ),
-                                );
-                            }
-                            _ => {
-                                renderer.set_format(
-                                    renderer.format().with_flags(FormatFlags::EMPHASIZED),
-                                );
-                            }
-                        }
-                    }
-                    Tag::BlockQuote => {
-                        renderer.set_format(renderer.format().with_added_indent(4));
-                    }
-                    Tag::CodeBlock(kind) => {
-                        let info = match kind {
-                            CodeBlockKind::Indented => "".into(),
-                            CodeBlockKind::Fenced(s) => s,
-                        };
-                        assert!(code_block.is_none());
-                        code_block = Some(CodeBlockConfig::from_info(&info)?);
-                    }
-                    Tag::List(first_item_number) => {
-                        lists.push(first_item_number);
-                    }
-                    Tag::Item => {
-                        let item = lists.last_mut().expect("non-empty list list");
-                        match *item {
-                            Some(n) => {
-                                let marker = format!("{:2}. ", n);
-                                renderer.write(&marker)?;
-                                renderer
-                                    .set_format(renderer.format().with_added_indent(marker.len()));
-                                *item.as_mut().unwrap() += 1;
-                            }
-                            None => {
-                                renderer.write("  - ")?;
-                                renderer.set_format(renderer.format().with_added_indent(4));
-                            }
-                        }
-                    }
-                    Tag::FootnoteDefinition(_s) => {}
-                    Tag::Table(_alignments) => {}
-                    Tag::TableHead => {}
-                    Tag::TableRow => {}
-                    Tag::TableCell => {}
-                    Tag::Emphasis => {
-                        renderer.set_format(renderer.format().with_flags(FormatFlags::UNDERLINE));
-                    }
-                    Tag::Strong => {
-                        renderer.set_format(renderer.format().with_flags(FormatFlags::EMPHASIZED));
-                    }
-                    Tag::Strikethrough => {
-                        renderer.set_format(renderer.format().with_strikethrough(true));
-                    }
-                    Tag::Link(_, _, _) => {}
-                    Tag::Image(_, _, _) => {}
-                }
+    // Split every document first, so a bad section block in a later
+    // row fails before anything is printed.  Without routes, section
+    // blocks are ordinary code blocks.
+    let routed: Vec<&str> = args.route.iter().map(|(name, _)| name.as_str()).collect();
+    let jobs = documents
+        .into_iter()
+        .map(|document| match routed.is_empty() {
+            true => Ok((document, Vec::new())),
+            false => split_sections(&document, &routed),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // With --data, the limit applies to the whole run, not to each row
+    if let Some(max_lines) = options.max_lines.filter(|_| jobs.len() > 1) {
+        let mut total = 0;
+        for (input, sections) in &jobs {
+            total += line_count(input, &options)?;
+            for (_, contents) in sections {
+                total += line_count(contents, &options)?;
             }
-            Event::End(tag) => match tag {
-                Tag::Paragraph => {
-                    renderer.write("\n\n")?;
-                }
-                Tag::Heading(_, _, _) => {
-                    // peel off everything but the centering command
-                    renderer.restore_format();
-                    renderer.write("\n\n")?;
-                    // peel off the centering command now that we're at
-                    // the start of a line
-                    renderer.restore_format();
-                }
-                Tag::BlockQuote => {
-                    renderer.restore_format();
-                }
-                Tag::CodeBlock(_) => {
-                    assert!(code_block.is_some());
-                    code_block = None;
-                }
-                Tag::List(_first_item_number) => {
-                    lists.pop();
-                    renderer.write("\n")?;
-                }
-                Tag::Item => {
-                    renderer.restore_format();
-                    renderer.write("\n")?;
-                }
-                Tag::FootnoteDefinition(_s) => {}
-                Tag::Table(_alignments) => {}
-                Tag::TableHead => {}
-                Tag::TableRow => {}
-                Tag::TableCell => {}
-                Tag::Emphasis => {
-                    renderer.restore_format();
-                }
-                Tag::Strong => {
-                    renderer.restore_format();
-                }
-                Tag::Strikethrough => {
-                    renderer.restore_format();
-                }
-                Tag::Link(_, _, _) => {}
-                Tag::Image(_, _, _) => {}
-            },
-            Event::Text(contents) => {
-                if let Some(block) = code_block.as_ref() {
-                    block.render(&mut renderer, &contents)?;
-                } else {
-                    renderer.write(&contents)?;
-                }
+        }
+        if total > max_lines {
+            bail!(
+                "refusing to print {} lines for {} rows, more than the limit of {}",
+                total,
+                jobs.len(),
+                max_lines
+            );
+        }
+    }
+
+    if let Some(style) = args.preview {
+        for (input, sections) in &jobs {
+            if sections.is_empty() || !input.trim().is_empty() {
+                print!("{}", preview(input, &options, style)?);
             }
-            Event::Code(contents) => {
-                renderer.set_format(renderer.format().with_red(true));
-                renderer.write(&contents)?;
-                renderer.restore_format();
+            for (name, contents) in sections {
+                let (_, path) = args
+                    .route
+                    .iter()
+                    .find(|(route, _)| route == name)
+                    .expect("section was routed");
+                println!("===== section '{}' to {} =====", name, path);
+                print!(
+                    "{}",
+                    preview(contents, &options, style)
+                        .with_context(|| format!("previewing section '{}'", name))?
+                );
             }
-            Event::Html(_e) => {}
-            Event::FootnoteReference(_e) => {}
-            Event::SoftBreak => {
-                renderer.write(" ")?;
+        }
+        return Ok(());
+    }
+
+    let _lockfile = lock(args.lock_file, args.lock_timeout)?;
+    let mut report = RenderReport::default();
+    // Outputs are kept open, so later documents follow earlier ones even
+    // in a regular file.  Open every routed device before sending
+    // anything, so a bad route doesn't leave a half-printed order.
+    let mut socket: Option<UnixStream> = None;
+    let mut devices: HashMap<PathBuf, File> = HashMap::new();
+    for (name, path) in &args.route {
+        cached_device(&mut devices, Path::new(path))
+            .with_context(|| format!("opening device for section '{}'", name))?;
+    }
+    for (input, sections) in &jobs {
+        let main_report = if !sections.is_empty() && input.trim().is_empty() {
+            // everything was routed elsewhere
+            RenderReport::default()
+        } else if let Some(path) = &args.socket {
+            if socket.is_none() {
+                socket = Some(UnixStream::connect(path).context("connecting to socket")?);
             }
-            Event::HardBreak => {
-                renderer.write("\n\n")?;
+            render(input, &options, socket.as_mut().unwrap())?
+        } else {
+            let path = args
+                .device
+                .as_ref()
+                .expect("device path required without socket");
+            render(input, &options, cached_device(&mut devices, path)?)?
+        };
+        report.low_paper |= main_report.low_paper;
+        report.dropped.merge(&main_report.dropped);
+        for (name, contents) in sections {
+            let (_, path) = args
+                .route
+                .iter()
+                .find(|(route, _)| route == name)
+                .expect("section was routed");
+            let section_report = cached_device(&mut devices, Path::new(path))
+                .and_then(|device| render(contents, &options, device))
+                .with_context(|| format!("printing section '{}' to {}", name, path))?;
+            report.low_paper |= section_report.low_paper;
+            report.dropped.merge(&section_report.dropped);
+        }
+    }
+
+    if args.report_dropped && !report.dropped.is_empty() {
+        eprintln!("Not printed as written: {}", report.dropped);
+    }
+
+    if report.low_paper {
+        eprintln!("Warning: printer paper is running low");
+    }
+    Ok(())
+}
+
+fn lock(path: Option<PathBuf>, timeout: Option<Duration>) -> Result<Option<File>> {
+    path.map(|path| -> Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .context("opening lockfile")?;
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                file.lock_exclusive().context("locking lockfile")?;
+                return Ok(file);
             }
-            Event::Rule => {
-                renderer.cut();
+        };
+        let start = Instant::now();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(file),
+                Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                    if timeout.is_zero() {
+                        bail!("printer is busy: {} is locked", path.display());
+                    }
+                    if start.elapsed() >= timeout {
+                        bail!(
+                            "timed out after {:?} waiting for lock on {}",
+                            timeout,
+                            path.display()
+                        );
+                    }
+                    sleep(Duration::from_millis(100).min(timeout));
+                }
+                Err(e) => return Err(e).context("locking lockfile"),
             }
-            Event::TaskListMarker(_checked) => {}
         }
+    })
+    .transpose()
+}
+
+fn parse_timeout(arg: &str) -> Result<Duration, String> {
+    let seconds: f64 = arg.parse().map_err(|e| format!("{}", e))?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err("expected a non-negative number of seconds".into());
+    }
+    // u64::MAX rounds up to 2^64 seconds, which Duration can't hold
+    if seconds >= u64::MAX as f64 {
+        return Err("timeout is too long".into());
     }
+    Ok(Duration::from_secs_f64(seconds))
+}
 
-    renderer.cut();
-    renderer.print()?;
+// Fill the template once for each data row.  The first row names the
+// columns.
+fn fill_data(template: &str, path: &Path) -> Result<Vec<String>> {
+    let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let delimiter = match path.extension() {
+        Some(ext) if ext == "tsv" => '\t',
+        _ => ',',
+    };
+    let rows = parse_table(decode_input(&bytes)?, delimiter)
+        .with_context(|| format!("parsing {}", path.display()))?;
+    let (header, rows) = match rows.split_first() {
+        Some((header, rows)) if !rows.is_empty() => (header, rows),
+        _ => bail!("no data rows in {}", path.display()),
+    };
+    Ok(rows
+        .iter()
+        .map(|row| fill_template(template, header, row))
+        .collect())
+}
 
-    Ok(())
+fn cached_device<'a>(devices: &'a mut HashMap<PathBuf, File>, path: &Path) -> Result<&'a mut File> {
+    Ok(match devices.entry(path.into()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(open_device(Some(path.into()))?),
+    })
+}
+
+fn open_device(path: Option<PathBuf>) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path.expect("device path required without socket"))
+        .context("opening output")
+}
+
+// Parse a pair of numbers separated by a comma.
+fn parse_pair(arg: &str) -> Result<(usize, usize), String> {
+    let (first, second) = arg.split_once(',').ok_or("expected N,N")?;
+    let parse = |value: &str| value.parse().map_err(|e| format!("{}: {}", value, e));
+    Ok((parse(first)?, parse(second)?))
+}
+
+// Decode UTF-8 input, dropping the byte order mark that some Windows
+// editors add.  Otherwise it would print as a stray '?'.
+fn decode_input(bytes: &[u8]) -> Result<&str> {
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    std::str::from_utf8(bytes).context("couldn't decode input")
+}
+
+// Split an argument at the first '=', so the value can contain '='
+// but the key can't.
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some(("", _)) => Err("key is empty".into()),
+        Some((key, value)) => Ok((key.into(), value.into())),
+        None => Err("expected KEY=VALUE".into()),
+    }
 }
 
 #[cfg(test)]
@@ -289,4 +490,92 @@ mod tests {
         use clap::CommandFactory;
         Args::command().debug_assert()
     }
+
+    #[test]
+    fn bom() {
+        assert_eq!(decode_input(b"\xef\xbb\xbf# Title\n").unwrap(), "# Title\n");
+        assert_eq!(decode_input(b"# Title\n").unwrap(), "# Title\n");
+        // only a leading BOM is removed
+        assert_eq!(decode_input(b"a\xef\xbb\xbf").unwrap(), "a\u{feff}");
+        decode_input(b"\xef\xbb").unwrap_err();
+
+        let mut output = std::io::Cursor::new(Vec::new());
+        let input = decode_input(b"\xef\xbb\xbffoo\n").unwrap();
+        render(input, &Default::default(), &mut output).unwrap();
+        let output = output.into_inner();
+        assert!(output.windows(4).any(|w| w == b"foo\r"));
+        // the BOM isn't printed as an unknown character before the text
+        assert!(!output.windows(5).any(|w| w == b"?foo\r"));
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let path = std::env::temp_dir().join(format!("mintmark-{}-lock", std::process::id()));
+        let holder = lock(Some(path.clone()), None).unwrap().unwrap();
+        let start = Instant::now();
+        let err = lock(Some(path.clone()), Some(Duration::from_millis(300))).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        let err = lock(Some(path.clone()), Some(Duration::ZERO)).unwrap_err();
+        assert!(err.to_string().contains("busy"), "{}", err);
+        drop(holder);
+        lock(Some(path.clone()), Some(Duration::ZERO))
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(parse_timeout("1.5").unwrap(), Duration::from_millis(1500));
+        parse_timeout("-1").unwrap_err();
+        parse_timeout("x").unwrap_err();
+        parse_timeout("1e300").unwrap_err();
+        parse_timeout("18446744073709551616").unwrap_err();
+    }
+
+    #[test]
+    fn data() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("mintmark-{}-{}", std::process::id(), name));
+        let template = "# {{name}}\n\n{{qty}} left\n";
+
+        let csv = path("data.csv");
+        fs::write(&csv, "\u{feff}name,qty\nWidget,3\n\"Gizmo, large\"\n").unwrap();
+        assert_eq!(
+            fill_data(template, &csv).unwrap(),
+            ["# Widget\n\n3 left\n", "# Gizmo\\, large\n\n left\n"]
+        );
+
+        let tsv = path("data.tsv");
+        fs::write(&tsv, "name\tqty\nA, B\t1\n").unwrap();
+        assert_eq!(fill_data(template, &tsv).unwrap(), ["# A\\, B\n\n1 left\n"]);
+
+        fs::write(&csv, "name,qty\n").unwrap();
+        fill_data(template, &csv).unwrap_err();
+        fs::write(&csv, "").unwrap();
+        fill_data(template, &csv).unwrap_err();
+        fs::write(&csv, "name\n\"Widget\n").unwrap();
+        fill_data(template, &csv).unwrap_err();
+
+        fs::remove_file(&csv).unwrap();
+        fs::remove_file(&tsv).unwrap();
+        fill_data(template, &csv).unwrap_err();
+    }
+
+    #[test]
+    fn pair() {
+        assert_eq!(parse_pair("8,10").unwrap(), (8, 10));
+        parse_pair("8").unwrap_err();
+        parse_pair("8,x").unwrap_err();
+        parse_pair("8,10,12").unwrap_err();
+    }
+
+    #[test]
+    fn key_value() {
+        assert_eq!(
+            parse_key_value("{{total}}=a=b").unwrap(),
+            ("{{total}}".into(), "a=b".into())
+        );
+        assert_eq!(parse_key_value("x=").unwrap(), ("x".into(), "".into()));
+        parse_key_value("=x").unwrap_err();
+        parse_key_value("x").unwrap_err();
+    }
 }
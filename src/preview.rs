@@ -0,0 +1,298 @@
+/*
+ * Copyright 2026 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Text approximation of a printout, for checking layout without paper.
+
+use anyhow::{bail, Result};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Width of a cut separator, in characters
+const CUT_WIDTH: usize = 40;
+
+/// Printer motion units (half-dots) per preview character, the width of
+/// a narrow character
+pub(crate) const UNITS_PER_CHAR: usize = 8;
+
+/// Bit image dots per preview character.  Text and images both span the
+/// paper, 40 narrow characters or 200 dots.
+const DOTS_PER_CHAR: usize = 5;
+
+/// Quadrant block characters, indexed by set quadrants: 1 top left,
+/// 2 top right, 4 bottom left, 8 bottom right
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+/// How to show text formatting in a preview
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PreviewStyle {
+    /// ANSI escape sequences, for a terminal
+    Ansi,
+    /// Text only
+    Plain,
+}
+
+impl FromStr for PreviewStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "ansi" => Self::Ansi,
+            "plain" => Self::Plain,
+            _ => bail!("unknown preview style '{}'", s),
+        })
+    }
+}
+
+/// Formatting of one preview character
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct CellStyle {
+    pub(crate) bold: bool,
+    pub(crate) underline: bool,
+    pub(crate) italic: bool,
+    pub(crate) red: bool,
+    pub(crate) strikethrough: bool,
+    pub(crate) shade: bool,
+}
+
+impl CellStyle {
+    fn sgr(&self) -> String {
+        let mut codes = vec!["0"];
+        for (set, code) in [
+            (self.bold, "1"),
+            (self.italic, "3"),
+            (self.underline, "4"),
+            (self.strikethrough, "9"),
+            (self.red, "31"),
+            (self.shade, "100"),
+        ] {
+            if set {
+                codes.push(code);
+            }
+        }
+        format!("\x1b[{}m", codes.join(";"))
+    }
+}
+
+/// One character of a text line
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Cell {
+    pub(crate) ch: char,
+    pub(crate) style: CellStyle,
+    /// Printed double width, so shown followed by a space
+    pub(crate) double_width: bool,
+}
+
+/// One column of an 8-dot-high band of a bit image, with the top dot
+/// in the most significant bit
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct ImageColumn {
+    pub(crate) black: u8,
+    pub(crate) red: u8,
+}
+
+#[derive(Debug)]
+pub(crate) struct Preview {
+    style: PreviewStyle,
+    out: String,
+}
+
+impl Preview {
+    pub(crate) fn new(style: PreviewStyle) -> Self {
+        Self {
+            style,
+            out: String::new(),
+        }
+    }
+
+    /// Add a line of text, indented by `pad` characters.  Black and red
+    /// passes, strikethrough, and shading over the same characters are
+    /// combined into one line.
+    pub(crate) fn text_line(&mut self, pad: usize, cells: &[Cell]) {
+        self.out.push_str(&" ".repeat(pad));
+        let mut current = CellStyle::default();
+        for cell in cells {
+            if self.style == PreviewStyle::Ansi && cell.style != current {
+                self.out.push_str(&cell.style.sgr());
+                current = cell.style;
+            }
+            self.out.push(cell.ch);
+            if cell.double_width {
+                self.out.push(' ');
+            }
+        }
+        if current != CellStyle::default() {
+            self.out.push_str(&CellStyle::default().sgr());
+        }
+        self.end_line();
+    }
+
+    /// Add an 8-dot-high band of a bit image, starting `left` dots from
+    /// the edge of the paper.  Each character shows a 2x2 grid of
+    /// quadrants, each set if any dot within it is printed.
+    pub(crate) fn image_band(&mut self, left: usize, columns: &[ImageColumn]) {
+        let half_chars = ((left + columns.len()) * 2 + DOTS_PER_CHAR - 1) / DOTS_PER_CHAR;
+        let chars = (half_chars + 1) / 2;
+        // quadrant bits, and whether any black or red dots were seen, for
+        // each of the two rows of the band
+        let mut rows = vec![vec![(0u8, false, false); chars]; 2];
+        for (i, column) in columns.iter().enumerate() {
+            let half = (left + i) * 2 / DOTS_PER_CHAR;
+            let bits = column.black | column.red;
+            for (row, cells) in rows.iter_mut().enumerate() {
+                let dots = (bits << (row * 4)) & 0xf0;
+                if dots == 0 {
+                    continue;
+                }
+                let cell = &mut cells[half / 2];
+                // upper or lower half of the row's four dots
+                let shift = half % 2;
+                if dots & 0xc0 != 0 {
+                    cell.0 |= 1 << shift;
+                }
+                if dots & 0x30 != 0 {
+                    cell.0 |= 4 << shift;
+                }
+                cell.1 |= (column.black << (row * 4)) & 0xf0 != 0;
+                cell.2 |= (column.red << (row * 4)) & 0xf0 != 0;
+            }
+        }
+        for row in rows {
+            let mut red = false;
+            for (quadrants, black, cell_red) in row {
+                // black overprints red
+                let cell_red = cell_red && !black;
+                if self.style == PreviewStyle::Ansi && cell_red != red {
+                    self.out
+                        .push_str(if cell_red { "\x1b[31m" } else { "\x1b[0m" });
+                    red = cell_red;
+                }
+                self.out.push(QUADRANTS[quadrants as usize]);
+            }
+            if red {
+                self.out.push_str("\x1b[0m");
+            }
+            self.end_line();
+        }
+    }
+
+    pub(crate) fn cut(&mut self) {
+        let label = " cut ";
+        let dashes = (CUT_WIDTH - label.len()) / 2;
+        writeln!(
+            self.out,
+            "{}{}{}",
+            "-".repeat(dashes),
+            label,
+            "-".repeat(CUT_WIDTH - label.len() - dashes)
+        )
+        .unwrap();
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.out
+    }
+
+    fn end_line(&mut self) {
+        let len = self.out.trim_end_matches(' ').len();
+        self.out.truncate(len);
+        self.out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let cell = |ch, style| Cell {
+            ch,
+            style,
+            double_width: false,
+        };
+        let red = CellStyle {
+            red: true,
+            ..Default::default()
+        };
+        let bold = CellStyle {
+            bold: true,
+            ..Default::default()
+        };
+        let cells = [
+            cell('a', red),
+            cell('b', red),
+            cell(' ', Default::default()),
+            Cell {
+                ch: 'c',
+                style: bold,
+                double_width: true,
+            },
+        ];
+
+        let mut preview = Preview::new(PreviewStyle::Plain);
+        preview.text_line(2, &cells);
+        preview.text_line(0, &[]);
+        preview.cut();
+        assert_eq!(
+            preview.finish(),
+            "  ab c\n\n----------------- cut ------------------\n"
+        );
+
+        let mut preview = Preview::new(PreviewStyle::Ansi);
+        preview.text_line(0, &cells);
+        assert_eq!(preview.finish(), "\x1b[0;31mab\x1b[0m \x1b[0;1mc \x1b[0m\n");
+    }
+
+    #[test]
+    fn image() {
+        let black = |bits| ImageColumn {
+            black: bits,
+            red: 0,
+        };
+        let mut preview = Preview::new(PreviewStyle::Plain);
+        // 5 dots per character, so 2 or 3 per quadrant
+        let mut columns = vec![black(0xff); 5];
+        columns.extend([black(0xc0), black(0x03), black(0), black(0), black(0)]);
+        preview.image_band(5, &columns);
+        assert_eq!(preview.finish(), " █▘\n █▖\n");
+
+        let mut preview = Preview::new(PreviewStyle::Ansi);
+        let columns = [
+            ImageColumn {
+                black: 0,
+                red: 0xff,
+            },
+            ImageColumn {
+                black: 0x0f,
+                red: 0xf0,
+            },
+        ];
+        preview.image_band(0, &columns);
+        assert_eq!(preview.finish(), "\x1b[31m▌\x1b[0m\n▌\n");
+    }
+
+    #[test]
+    fn style() {
+        assert_eq!("ansi".parse::<PreviewStyle>().unwrap(), PreviewStyle::Ansi);
+        assert_eq!(
+            "plain".parse::<PreviewStyle>().unwrap(),
+            PreviewStyle::Plain
+        );
+        "html".parse::<PreviewStyle>().unwrap_err();
+    }
+}
@@ -18,14 +18,28 @@ use anyhow::{anyhow, bail, Context, Result};
 use bitflags::bitflags;
 use encoding::all::ASCII;
 use encoding::types::{EncoderTrap, Encoding};
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::io::{Read, Write};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
 
+use crate::preview::{Cell, CellStyle, ImageColumn, Preview, PreviewStyle, UNITS_PER_CHAR};
+use crate::status::Status;
 use crate::strike::StrikeImage;
 
-const LINE_PIXELS_IMAGE: usize = 200;
+pub(crate) const LINE_PIXELS_IMAGE: usize = 200;
 const LINE_PIXELS_TEXT: usize = 320;
+const MAX_BEEP_COUNT: u8 = 9;
+const MAX_DARKNESS: u8 = 3;
+// With paper-out handling, check the paper after sending at least this
+// much
+const PAPER_CHECK_BYTES: usize = 256;
+const PAPER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Default buzzer on/off time, in 2 ms units
+pub const BEEP_DEFAULT_TIME: u8 = 100;
 
 // generated by build.rs
 include!(concat!(env!("OUT_DIR"), "/custom.rs"));
@@ -33,6 +47,9 @@ include!(concat!(env!("OUT_DIR"), "/custom.rs"));
 pub struct Renderer<F: Read + Write> {
     device: F,
     buf: Vec<u8>,
+    // Offsets in buf just past each spooled line, where sending can
+    // pause without splitting a command
+    line_ends: Vec<usize>,
 
     format: Rc<Format>,
     stack: Vec<Rc<Format>>,
@@ -42,6 +59,77 @@ pub struct Renderer<F: Read + Write> {
 
     word: Vec<LineChar>,
     word_has_letters: bool,
+    break_chars: Vec<u8>,
+
+    lines_per_slip: Option<usize>,
+    slip_lines: usize,
+    total_lines: usize,
+
+    rtl: bool,
+    left_margin: usize,
+    darkness: u8,
+    metrics: FontMetrics,
+
+    wrap_marker: Option<u8>,
+    wrap_indent: usize,
+    continuation: bool,
+
+    replaced_chars: usize,
+
+    paper_out: Option<PaperOut>,
+    paper_wait: Option<fn()>,
+
+    preview: Option<Preview>,
+}
+
+/// What to do if the printer runs out of paper while a job is being sent
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PaperOut {
+    /// Stop sending until paper is loaded, then continue
+    Wait,
+    /// Stop sending and fail
+    Abort,
+}
+
+impl FromStr for PaperOut {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "wait" => Self::Wait,
+            "abort" => Self::Abort,
+            _ => bail!("unknown paper-out action '{}'", s),
+        })
+    }
+}
+
+/// Character widths of the printer's fonts, before any double-width
+/// scaling.  The defaults match the TM-U220B, but other firmware or font
+/// revisions may differ slightly, causing text to wrap early or late and
+/// strikethrough to drift out of line with the text.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FontMetrics {
+    /// Character pitch of the narrow font, in half-dots
+    pub narrow_width: usize,
+    /// Character pitch of the wide font, in half-dots
+    pub wide_width: usize,
+    /// Width of a strikethrough or shade segment for one narrow
+    /// character, in bit image dots
+    pub narrow_overstrike: usize,
+    /// Width of a strikethrough or shade segment for one wide character,
+    /// in bit image dots
+    pub wide_overstrike: usize,
+}
+
+impl Default for FontMetrics {
+    fn default() -> Self {
+        Self {
+            narrow_width: 8,
+            wide_width: 10,
+            narrow_overstrike: 5,
+            wide_overstrike: 6,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -52,6 +140,8 @@ pub struct Format {
     red: bool,
     unidirectional: bool,
     strikethrough: bool,
+    shade: bool,
+    italic: bool,
     justification: Justification,
     control: bool,
 }
@@ -70,7 +160,6 @@ bitflags! {
 pub enum Justification {
     Left = 0,
     Center = 1,
-    #[allow(dead_code)]
     Right = 2,
 }
 
@@ -78,6 +167,10 @@ pub enum Justification {
 struct LineChar {
     char: u8,
     format: Rc<Format>,
+    rtl: bool,
+    // One character cell of an inline image, as bit image columns to
+    // print in place of the character
+    image: Option<Rc<[u8]>>,
 }
 
 impl<F: Read + Write> Renderer<F> {
@@ -85,12 +178,28 @@ impl<F: Read + Write> Renderer<F> {
         let mut renderer = Renderer::<F> {
             device,
             buf: Vec::new(),
+            line_ends: Vec::new(),
             format: Format::new(),
             stack: Vec::new(),
             line: Vec::new(),
             line_width: 0,
             word: Vec::new(),
             word_has_letters: false,
+            break_chars: Vec::new(),
+            lines_per_slip: None,
+            slip_lines: 0,
+            total_lines: 0,
+            rtl: false,
+            left_margin: 0,
+            darkness: 1,
+            metrics: FontMetrics::default(),
+            wrap_marker: None,
+            wrap_indent: 0,
+            continuation: false,
+            replaced_chars: 0,
+            paper_out: None,
+            paper_wait: None,
+            preview: None,
         };
         // Reset printer
         renderer.spool(b"\x1b@");
@@ -99,6 +208,127 @@ impl<F: Read + Write> Renderer<F> {
         renderer
     }
 
+    pub fn set_lines_per_slip(&mut self, lines: Option<usize>) {
+        self.lines_per_slip = lines;
+    }
+
+    // Set the left margin in printer motion units (half-dots, the same
+    // units as text widths).  Images are in full dots, two units each.
+    pub fn set_left_margin(&mut self, margin: u16) -> Result<()> {
+        if margin as usize > LINE_PIXELS_TEXT / 2 {
+            bail!(
+                "Left margin {} larger than maximum {}",
+                margin,
+                LINE_PIXELS_TEXT / 2
+            );
+        }
+        if self.left_margin != margin as usize {
+            self.left_margin = margin.into();
+            self.spool(b"\x1dL");
+            self.spool(&margin.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn line_pixels_text(&self) -> usize {
+        LINE_PIXELS_TEXT - self.left_margin
+    }
+
+    pub fn line_pixels_image(&self) -> usize {
+        LINE_PIXELS_IMAGE - (self.left_margin + 1) / 2
+    }
+
+    // Also record each line as text, for previewing without a printer
+    pub fn set_preview(&mut self, style: Option<PreviewStyle>) {
+        self.preview = style.map(Preview::new);
+    }
+
+    // The preview recorded so far, if enabled
+    pub fn take_preview(&mut self) -> Option<String> {
+        self.preview.take().map(Preview::finish)
+    }
+
+    // Allow soft line breaks after these characters, in addition to
+    // spaces.  The character stays at the end of the line.
+    pub fn set_break_chars(&mut self, chars: &str) -> Result<()> {
+        if let Some(ch) = chars.chars().find(|ch| !ch.is_ascii_graphic()) {
+            bail!("Invalid break character {:?}", ch);
+        }
+        self.break_chars = chars.bytes().collect();
+        Ok(())
+    }
+
+    // Darken all output: 1 is normal, 2 double-strikes every dot, and 3
+    // also prints in one direction only, for better dot alignment.
+    pub fn set_darkness(&mut self, darkness: u8) -> Result<()> {
+        if !(1..=MAX_DARKNESS).contains(&darkness) {
+            bail!("Darkness {} not between 1 and {}", darkness, MAX_DARKNESS);
+        }
+        self.darkness = darkness;
+        Ok(())
+    }
+
+    // Send output in chunks, checking after each one whether the printer
+    // has run out of paper.  Bytes already sent stay in the printer's
+    // receive buffer and print once paper is loaded.
+    pub fn set_paper_out(&mut self, action: Option<PaperOut>) {
+        self.paper_out = action;
+    }
+
+    // Call this when PaperOut::Wait starts waiting for paper to be loaded
+    pub fn set_paper_wait_callback(&mut self, callback: Option<fn()>) {
+        self.paper_wait = callback;
+    }
+
+    // End each soft-wrapped line with a marker character, and indent the
+    // continuation by the given number of characters, so soft wraps can
+    // be told apart from hard line breaks.
+    pub fn set_wrap_style(&mut self, marker: Option<char>, indent: usize) -> Result<()> {
+        match marker {
+            Some(ch) if !ch.is_ascii_graphic() => bail!("Invalid wrap marker {:?}", ch),
+            _ => self.wrap_marker = marker.map(|ch| ch as u8),
+        }
+        self.wrap_indent = indent;
+        Ok(())
+    }
+
+    pub fn set_font_metrics(&mut self, metrics: FontMetrics) -> Result<()> {
+        let FontMetrics {
+            narrow_width,
+            wide_width,
+            narrow_overstrike,
+            wide_overstrike,
+        } = metrics;
+        if [narrow_width, wide_width, narrow_overstrike, wide_overstrike].contains(&0) {
+            bail!("Font metrics must be nonzero: {:?}", metrics);
+        }
+        // Keep the overstrike for a full line of text within a bit image
+        // line.  Double-width lines have half as many characters, so they
+        // can't be longer.
+        for (name, width, overstrike) in [
+            ("narrow", narrow_width, narrow_overstrike),
+            ("wide", wide_width, wide_overstrike),
+        ] {
+            let line = LINE_PIXELS_TEXT / width * overstrike;
+            if line > LINE_PIXELS_IMAGE {
+                bail!(
+                    "Overstrike for a line of {} characters would be {} dots, more than maximum {}",
+                    name,
+                    line,
+                    LINE_PIXELS_IMAGE
+                );
+            }
+        }
+        self.metrics = metrics;
+        Ok(())
+    }
+
+    // Enable basic right-to-left support: the first through last RTL
+    // characters on each line are reversed, and the line is right-aligned.
+    pub fn set_rtl(&mut self, rtl: bool) {
+        self.rtl = rtl;
+    }
+
     pub fn format(&self) -> Rc<Format> {
         self.format.clone()
     }
@@ -113,23 +343,34 @@ impl<F: Read + Write> Renderer<F> {
     }
 
     fn set_printer_format(&mut self, format: &Format) {
+        let mut flags = format.flags;
+        if self.darkness >= 2 {
+            flags |= FormatFlags::EMPHASIZED;
+        }
         self.spool(b"\x1b!");
-        self.spool(&[format.flags.bits]);
+        self.spool(&[flags.bits]);
         self.spool(b"\x1b3");
         self.spool(&[format.line_spacing]);
         self.spool(b"\x1br");
         self.spool(&[format.red as u8]);
         self.spool(b"\x1bU");
-        self.spool(&[format.unidirectional as u8]);
+        self.spool(&[(format.unidirectional || self.darkness >= 3) as u8]);
         self.spool(b"\x1ba");
         self.spool(&[format.justification as u8]);
+        if HAVE_ITALIC {
+            // italic glyphs are the only custom characters
+            self.spool(b"\x1b%");
+            self.spool(&[format.italic as u8]);
+        }
     }
 
     pub fn write(&mut self, contents: &str) -> Result<()> {
         let mut bytes = ASCII
             .encode(contents, EncoderTrap::Replace)
             .map_err(|e| anyhow!(e).context("encoding text"))?;
-        for byte in &mut bytes {
+        // The ASCII encoder replaces each unencodable char with a single
+        // byte, so bytes and chars correspond one-to-one.
+        for (byte, ch) in bytes.iter_mut().zip(contents.chars()) {
             // Got to the next word break?  Write out the word.
             if self.word_has_letters && (*byte == b'\n' || *byte == b' ') {
                 // Start a new word.
@@ -144,76 +385,133 @@ impl<F: Read + Write> Renderer<F> {
             if (*byte < 0x20 || *byte > 0x7e) && *byte != b'\t' {
                 *byte = b'?';
             }
+            if *byte as char != ch {
+                self.replaced_chars += 1;
+            }
             // Printables and spaces go in the word.  Once we have at
             // least one printable, the word becomes eligible for writing.
             self.word.push(LineChar {
                 char: *byte,
                 format: self.format.clone(),
+                rtl: is_rtl(ch),
+                image: None,
             });
             if *byte != b' ' {
                 self.word_has_letters = true;
             }
+            // Break characters end the word they're in
+            if self.break_chars.contains(byte) {
+                self.write_word();
+            }
         }
         Ok(())
     }
 
     fn write_word(&mut self) {
-        let width = self
-            .word
-            .iter()
-            .fold(0, |acc, lc| acc + lc.format.char_bounding_width());
+        let width = self.word.iter().fold(0, |acc, lc| {
+            acc + lc.format.char_bounding_width(&self.metrics)
+        });
+        // Leave room for a wrap marker at the end of every line
+        let reserved = match self.wrap_marker {
+            Some(_) => self.format.char_bounding_width(&self.metrics),
+            None => 0,
+        };
 
         // If we have a partial line and this word won't fit on it, start
         // a new line.
-        let soft_wrapped =
-            if width <= LINE_PIXELS_TEXT && self.line_width + width > LINE_PIXELS_TEXT {
-                self.spool_line();
-                true
-            } else {
-                false
-            };
+        let soft_wrapped = if width <= self.line_pixels_text()
+            && self.line_width + width + reserved > self.line_pixels_text()
+        {
+            self.soft_wrap();
+            true
+        } else {
+            false
+        };
 
         // Ignore spaces at the beginning of a soft-wrapped line, then
-        // push the rest of the word.
-        for lc in self
-            .word
-            .clone()
-            .drain(..)
-            .filter(|lc| !soft_wrapped || lc.char != b' ')
-        {
-            let char_width = lc.format.char_bounding_width();
+        // push the rest of the word.  Move the word out of self so
+        // spool_line() can run while we drain it.
+        let mut word = std::mem::take(&mut self.word);
+        for lc in word.drain(..).filter(|lc| !soft_wrapped || lc.char != b' ') {
+            let char_width = lc.format.char_bounding_width(&self.metrics);
 
             // If we've reached the end of the line just within this word,
             // just break in the middle of the word.
-            if self.line_width + char_width > LINE_PIXELS_TEXT {
-                self.spool_line();
+            if self.line_width + char_width + reserved > self.line_pixels_text() {
+                self.soft_wrap();
             }
 
             // Add indent if at the beginning of the line
             if self.line_width == 0 {
-                for _ in 0..lc.format.indent {
+                let mut indent = self.effective_indent(&lc.format);
+                if self.continuation {
+                    indent = min(
+                        indent + self.wrap_indent,
+                        self.line_pixels_text() / 2 / char_width,
+                    );
+                }
+                for _ in 0..indent {
                     self.line.push(LineChar {
                         char: b' ',
                         format: lc.format.clone(),
+                        rtl: false,
+                        image: None,
                     })
                 }
-                self.line_width += lc.format.indent * char_width;
+                self.line_width += indent * char_width;
             }
 
             self.line.push(lc);
             self.line_width += char_width;
         }
 
-        self.word.clear();
+        // Keep the allocation for the next word
+        self.word = word;
         self.word_has_letters = false;
     }
 
+    // End the line because the next word doesn't fit, adding the wrap
+    // marker if there is one
+    fn soft_wrap(&mut self) {
+        if let Some(marker) = self.wrap_marker {
+            let format = match self.line.last() {
+                Some(lc) => lc.format.clone(),
+                None => self.format.clone(),
+            };
+            self.line.push(LineChar {
+                char: marker,
+                format,
+                rtl: false,
+                image: None,
+            });
+        }
+        self.spool_line();
+        self.continuation = true;
+    }
+
+    // Deeply nested blocks could indent past the end of the line, so
+    // leave at least half the line for text.
+    fn effective_indent(&self, format: &Format) -> usize {
+        min(
+            format.indent,
+            self.line_pixels_text() / 2 / format.char_bounding_width(&self.metrics),
+        )
+    }
+
+    // The number of characters in the current format that fit on a line
+    // after the indent, leaving room for any wrap marker
+    pub fn line_columns(&self) -> usize {
+        let reserved = self.wrap_marker.is_some() as usize;
+        (self.line_pixels_text() / self.format.char_bounding_width(&self.metrics))
+            .saturating_sub(self.effective_indent(&self.format) + reserved)
+    }
+
     pub fn write_image(&mut self, image: &StrikeImage) -> Result<()> {
-        if image.width() as usize > LINE_PIXELS_IMAGE {
+        if image.width() as usize > self.line_pixels_image() {
             bail!(
                 "Image width {} larger than maximum {}",
                 image.width(),
-                LINE_PIXELS_IMAGE
+                self.line_pixels_image()
             );
         }
 
@@ -264,6 +562,8 @@ impl<F: Read + Write> Renderer<F> {
                         self.line.push(LineChar {
                             char: byte,
                             format: self.format.clone(),
+                            rtl: false,
+                            image: None,
                         })
                     }
                     for x in xrange.clone() {
@@ -282,11 +582,15 @@ impl<F: Read + Write> Renderer<F> {
                         self.line.push(LineChar {
                             char: byte,
                             format: self.format.clone(),
+                            rtl: false,
+                            image: None,
                         });
                     }
                     self.line.push(LineChar {
                         char: b'\r',
                         format: self.format.clone(),
+                        rtl: false,
+                        image: None,
                     })
                 }
                 self.restore_format();
@@ -308,46 +612,280 @@ impl<F: Read + Write> Renderer<F> {
             self.spool_line();
         }
 
-        self.spool(b"\x1dV\x42\x50")
+        self.spool(b"\x1dV\x42\x50");
+        self.slip_lines = 0;
+        if let Some(preview) = self.preview.as_mut() {
+            preview.cut();
+        }
+    }
+
+    // Cut if we've printed at least lines_per_slip lines since the last
+    // cut.  Callers should only invoke this at a block boundary, so we
+    // don't split images or code blocks across slips.
+    pub fn cut_if_slip_full(&mut self) {
+        if let Some(lines) = self.lines_per_slip {
+            if self.slip_lines >= lines {
+                self.cut();
+            }
+        }
+    }
+
+    // Cut unless nothing has been printed since the last cut, so a new
+    // slip can be started without leaving a blank one.
+    pub fn cut_if_slip_started(&mut self) {
+        if self.slip_lines > 0 || self.line_width > 0 {
+            self.cut();
+        }
+    }
+
+    // Add a small image to the current word as a run of character
+    // cells, each as wide as a character's overstrike, so it flows with
+    // the text and prints in the text color.  The image must fit in a
+    // single 8-dot bit image row.
+    pub fn write_inline_image(&mut self, image: &StrikeImage) -> Result<()> {
+        if image.height() > 8 {
+            bail!(
+                "Inline image height {} larger than maximum 8",
+                image.height()
+            );
+        }
+        let cell_width = self.format.char_overstrike_width(&self.metrics);
+        let width = image.width() as usize;
+        let cells = (width + cell_width - 1) / cell_width;
+        if cells * self.format.char_bounding_width(&self.metrics) > self.line_pixels_text() {
+            bail!("Inline image width {} larger than the line", width);
+        }
+        let columns: Vec<u8> = (0..cells * cell_width)
+            .map(|x| {
+                (0..8u32).fold(0u8, |byte, y| {
+                    let dot =
+                        x < width && y < image.height() && image.get_pixel(x as u32, y).0 != [0, 0];
+                    byte << 1 | dot as u8
+                })
+            })
+            .collect();
+        for cell in columns.chunks(cell_width) {
+            self.word.push(LineChar {
+                // never printed, but must not be a space, so the cell
+                // isn't dropped at the start of a wrapped line
+                char: b'#',
+                format: self.format.clone(),
+                rtl: false,
+                image: Some(cell.into()),
+            });
+        }
+        if cells > 0 {
+            self.word_has_letters = true;
+        }
+        Ok(())
+    }
+
+    // Number of lines spooled so far, counting each row of an image
+    pub fn line_count(&self) -> usize {
+        self.total_lines
+    }
+
+    // Number of characters printed as '?' because the printer can't
+    // print them
+    pub fn replaced_chars(&self) -> usize {
+        self.replaced_chars
+    }
+
+    // Sound the optional buzzer, which is driven from the drawer kick-out
+    // connector.  We can't detect whether a buzzer is attached; if not,
+    // the command has no effect.
+    pub fn beep(&mut self, count: u8, on: u8, off: u8) -> Result<()> {
+        if count == 0 || count > MAX_BEEP_COUNT {
+            bail!("Beep count {} not between 1 and {}", count, MAX_BEEP_COUNT);
+        }
+        if on == 0 || off == 0 {
+            bail!("Beep on and off times must be nonzero");
+        }
+
+        // Flush line buffer if non-empty
+        if self.line_width > 0 {
+            self.spool_line();
+        }
+
+        for _ in 0..count {
+            // Pulse connector pin 2 for on * 2 ms, then wait off * 2 ms
+            self.spool(b"\x1bp\x00");
+            self.spool(&[on, off]);
+        }
+        Ok(())
     }
 
     fn spool_line(&mut self) {
+        if self.rtl {
+            self.reorder_rtl();
+        }
+        self.preview_line();
+        // Move the line out of self so we can borrow it while spooling
+        let mut line = std::mem::take(&mut self.line);
         for pass in PASSES.iter() {
-            if !self.active_for_line(pass) {
+            if !active_for_line(&line, pass) {
                 continue;
             }
             // active_for_line() returned true, so there is at least one LineChar
-            let mut format = self.line[0].format.clone();
+            let mut format = line[0].format.clone();
             let mut active = (pass.active)(&format);
             self.set_printer_format(&(pass.format_map)((*format).clone(), active));
-            for lc in self.line.clone().iter() {
+            for lc in line.iter() {
                 if *format != *lc.format {
                     format = lc.format.clone();
                     active = (pass.active)(&format);
                     self.set_printer_format(&(pass.format_map)((*format).clone(), active));
                 }
-                self.spool(&(pass.char_map)(lc.char, &format, active));
+                (pass.char_map)(lc, &format, &self.metrics, active, &mut self.buf);
             }
             self.spool(b"\r");
         }
         self.spool(b"\n");
+        self.line_ends.push(self.buf.len());
 
-        self.line.clear();
+        // Keep the allocation for the next line
+        line.clear();
+        self.line = line;
         self.line_width = 0;
+        self.continuation = false;
+        self.slip_lines += 1;
+        self.total_lines += 1;
     }
 
-    fn active_for_line(&self, pass: &LinePass) -> bool {
-        self.line.iter().any(|lc| (pass.active)(&lc.format))
+    // Add the buffered line to the preview as it will appear on paper,
+    // with its passes merged
+    fn preview_line(&mut self) {
+        let mut preview = match self.preview.take() {
+            Some(preview) => preview,
+            None => return,
+        };
+        // Bit image rows consist only of control characters, and are
+        // empty if the row has no dots
+        if self.line_width > 0 && self.line.iter().all(|lc| lc.format.control) {
+            // Each strike is a bit image prologue, the columns, and a
+            // carriage return
+            let mut columns: Vec<ImageColumn> = Vec::new();
+            let mut i = 0;
+            while i + 5 <= self.line.len() {
+                let width =
+                    u16::from_le_bytes([self.line[i + 3].char, self.line[i + 4].char]) as usize;
+                let red = self.line[i].format.red;
+                if columns.len() < width {
+                    columns.resize(width, Default::default());
+                }
+                for (column, lc) in columns.iter_mut().zip(&self.line[i + 5..i + 5 + width]) {
+                    if red {
+                        column.red |= lc.char;
+                    } else {
+                        column.black |= lc.char;
+                    }
+                }
+                i += 5 + width + 1;
+            }
+            // Images are centered
+            let left =
+                self.line_pixels_image().saturating_sub(columns.len()) / 2 + self.left_margin / 2;
+            preview.image_band(left, &columns);
+        } else {
+            let cells: Vec<Cell> = self
+                .line
+                .iter()
+                .map(|lc| Cell {
+                    ch: match lc.image {
+                        Some(_) => '▒',
+                        None => lc.char as char,
+                    },
+                    style: CellStyle {
+                        bold: lc.format.flags.contains(FormatFlags::EMPHASIZED),
+                        underline: lc.format.flags.contains(FormatFlags::UNDERLINE),
+                        italic: lc.format.italic,
+                        red: lc.format.red,
+                        strikethrough: lc.format.strikethrough,
+                        shade: lc.format.shade,
+                    },
+                    double_width: lc.format.flags.contains(FormatFlags::DOUBLE_WIDTH),
+                })
+                .collect();
+            let free = self.line_pixels_text().saturating_sub(self.line_width);
+            let offset = match self.line.first().map(|lc| lc.format.justification) {
+                Some(Justification::Center) => free / 2,
+                Some(Justification::Right) => free,
+                _ => 0,
+            };
+            preview.text_line((self.left_margin + offset) / UNITS_PER_CHAR, &cells);
+        }
+        self.preview = Some(preview);
+    }
+
+    // Reverse the visual order of the (single) RTL run in the line, from
+    // the first RTL character through the last one, and right-align the
+    // line unless it's already centered.  This isn't the Unicode bidi
+    // algorithm: multiple runs, or LTR text embedded in an RTL run, will
+    // be misordered.
+    fn reorder_rtl(&mut self) {
+        let first = self.line.iter().position(|lc| lc.rtl);
+        let last = self.line.iter().rposition(|lc| lc.rtl);
+        if let (Some(first), Some(last)) = (first, last) {
+            self.line[first..=last].reverse();
+            for lc in self.line.iter_mut() {
+                if lc.format.justification == Justification::Left {
+                    lc.format = lc.format.with_justification(Justification::Right);
+                }
+            }
+        }
     }
 
     fn spool(&mut self, buf: &[u8]) {
         self.buf.extend_from_slice(buf);
     }
 
+    // Query printer status.  Call after print().
+    pub fn status(&mut self) -> Result<Status> {
+        Status::read(&mut self.device)
+    }
+
     pub fn print(&mut self) -> Result<()> {
-        self.device
-            .write_all(&self.buf)
-            .context("writing to device")?;
+        let action = match self.paper_out {
+            Some(action) => action,
+            None => {
+                self.device
+                    .write_all(&self.buf)
+                    .context("writing to device")?;
+                self.buf.clear();
+                self.line_ends.clear();
+                return Ok(());
+            }
+        };
+        // Send whole lines, at least PAPER_CHECK_BYTES at a time
+        let mut sent = 0;
+        let line_ends = std::mem::take(&mut self.line_ends);
+        for end in line_ends.into_iter().chain([self.buf.len()]) {
+            if end <= sent || (end - sent < PAPER_CHECK_BYTES && end < self.buf.len()) {
+                continue;
+            }
+            self.device
+                .write_all(&self.buf[sent..end])
+                .context("writing to device")?;
+            sent = end;
+            let mut waiting = false;
+            while Status::read(&mut self.device)?.out_of_paper() {
+                match action {
+                    PaperOut::Abort => bail!(
+                        "printer ran out of paper after {} of {} bytes were sent",
+                        sent,
+                        self.buf.len()
+                    ),
+                    PaperOut::Wait if !waiting => {
+                        if let Some(callback) = self.paper_wait {
+                            callback();
+                        }
+                        waiting = true;
+                    }
+                    PaperOut::Wait => {}
+                }
+                sleep(PAPER_POLL_INTERVAL);
+            }
+        }
         self.buf.clear();
         Ok(())
     }
@@ -362,6 +900,8 @@ impl Format {
             red: false,
             unidirectional: false,
             strikethrough: false,
+            shade: false,
+            italic: false,
             justification: Justification::Left,
             control: false,
         })
@@ -409,6 +949,23 @@ impl Format {
         Rc::new(format)
     }
 
+    // Use the custom italic glyphs if we have them, otherwise underline
+    pub fn with_italic(&self) -> Rc<Self> {
+        if HAVE_ITALIC {
+            let mut format = self.clone();
+            format.italic = true;
+            Rc::new(format)
+        } else {
+            self.with_flags(FormatFlags::UNDERLINE)
+        }
+    }
+
+    pub fn with_shade(&self, shade: bool) -> Rc<Self> {
+        let mut format = self.clone();
+        format.shade = shade;
+        Rc::new(format)
+    }
+
     pub fn with_justification(&self, justification: Justification) -> Rc<Self> {
         let mut format = self.clone();
         format.justification = justification;
@@ -421,11 +978,11 @@ impl Format {
         Rc::new(format)
     }
 
-    fn char_bounding_width(&self) -> usize {
-        let mut width: usize = if !(self.flags & FormatFlags::NARROW).is_empty() {
-            8
+    fn char_bounding_width(&self, metrics: &FontMetrics) -> usize {
+        let mut width = if !(self.flags & FormatFlags::NARROW).is_empty() {
+            metrics.narrow_width
         } else {
-            10
+            metrics.wide_width
         };
         if !(self.flags & FormatFlags::DOUBLE_WIDTH).is_empty() {
             width *= 2
@@ -433,11 +990,11 @@ impl Format {
         width
     }
 
-    fn char_overstrike_width(&self) -> usize {
-        let mut width: usize = if !(self.flags & FormatFlags::NARROW).is_empty() {
-            5
+    fn char_overstrike_width(&self, metrics: &FontMetrics) -> usize {
+        let mut width = if !(self.flags & FormatFlags::NARROW).is_empty() {
+            metrics.narrow_overstrike
         } else {
-            6
+            metrics.wide_overstrike
         };
         if !(self.flags & FormatFlags::DOUBLE_WIDTH).is_empty() {
             width *= 2
@@ -446,11 +1003,26 @@ impl Format {
     }
 }
 
-fn bit_image_prologue(width: usize) -> Result<Vec<u8>> {
+fn is_rtl(ch: char) -> bool {
+    matches!(ch,
+        // Hebrew, Arabic, Syriac, Thaana, NKo, Samaritan, Mandaic
+        '\u{0590}'..='\u{085f}'
+        // Arabic Extended-A
+        | '\u{08a0}'..='\u{08ff}'
+        // Hebrew and Arabic presentation forms
+        | '\u{fb1d}'..='\u{fdff}'
+        | '\u{fe70}'..='\u{feff}')
+}
+
+fn bit_image_prologue(width: usize) -> Result<[u8; 5]> {
     let width_u16 = u16::try_from(width).context("bit image width too large")?;
     let width_bytes = &width_u16.to_le_bytes();
     // Bit image mode 0, vert 72 dpi, horz 80 dpi, width 200 dots
-    Ok(vec![0x1b, b'*', 0, width_bytes[0], width_bytes[1]])
+    Ok([0x1b, b'*', 0, width_bytes[0], width_bytes[1]])
+}
+
+fn active_for_line(line: &[LineChar], pass: &LinePass) -> bool {
+    line.iter().any(|lc| (pass.active)(&lc.format))
 }
 
 struct LinePass {
@@ -458,21 +1030,77 @@ struct LinePass {
     name: &'static str,
     active: fn(format: &Format) -> bool,
     format_map: fn(format: Format, active: bool) -> Format,
-    char_map: fn(char: u8, format: &Format, active: bool) -> Vec<u8>,
+    // Append the bytes for one character to the output buffer
+    char_map:
+        fn(lc: &LineChar, format: &Format, metrics: &FontMetrics, active: bool, out: &mut Vec<u8>),
 }
 
-fn strikethrough_char_map(_char: u8, format: &Format, active: bool) -> Vec<u8> {
+fn strikethrough_char_map(
+    _lc: &LineChar,
+    format: &Format,
+    metrics: &FontMetrics,
+    active: bool,
+    out: &mut Vec<u8>,
+) {
     if active {
-        let char_width = format.char_overstrike_width();
-        let mut ret = bit_image_prologue(char_width).expect("overstrike width larger than u16");
-        ret.resize(ret.len() + char_width, 0x10);
-        ret
+        let char_width = format.char_overstrike_width(metrics);
+        out.extend(bit_image_prologue(char_width).expect("overstrike width larger than u16"));
+        out.resize(out.len() + char_width, 0x10);
     } else {
-        vec![b' ']
+        out.push(b' ');
+    }
+}
+
+fn shade_char_map(
+    _lc: &LineChar,
+    format: &Format,
+    metrics: &FontMetrics,
+    active: bool,
+    out: &mut Vec<u8>,
+) {
+    if active {
+        let char_width = format.char_overstrike_width(metrics);
+        out.extend(bit_image_prologue(char_width).expect("shade width larger than u16"));
+        // Sparse staggered dots, light enough not to obscure the text
+        out.extend((0..char_width).map(|x| match x % 4 {
+            1 => 0x22,
+            3 => 0x88,
+            _ => 0,
+        }));
+    } else {
+        out.push(b' ');
+    }
+}
+
+fn text_char_map(
+    lc: &LineChar,
+    format: &Format,
+    _metrics: &FontMetrics,
+    active: bool,
+    out: &mut Vec<u8>,
+) {
+    match (&lc.image, active) {
+        (Some(columns), true) => {
+            out.extend(bit_image_prologue(columns.len()).expect("inline image cell too wide"));
+            out.extend(columns.iter());
+        }
+        (None, true) => out.push(lc.char),
+        (_, false) if !format.control => out.push(b' '),
+        _ => {}
     }
 }
 
-static PASSES: [LinePass; 4] = [
+static PASSES: [LinePass; 5] = [
+    LinePass {
+        name: "shade",
+        active: |format| format.shade,
+        format_map: |mut format, _active| {
+            format.red = false;
+            format.flags &= !FormatFlags::UNDERLINE;
+            format
+        },
+        char_map: shade_char_map,
+    },
     LinePass {
         name: "black",
         active: |format| !format.red,
@@ -483,15 +1111,7 @@ static PASSES: [LinePass; 4] = [
             };
             format
         },
-        char_map: |char, format, active| {
-            if active {
-                vec![char]
-            } else if format.control {
-                vec![]
-            } else {
-                vec![b' ']
-            }
-        },
+        char_map: text_char_map,
     },
     LinePass {
         name: "black strikethrough",
@@ -499,8 +1119,10 @@ static PASSES: [LinePass; 4] = [
         format_map: |mut format, active| {
             if !active {
                 format.red = false;
-                format.flags &= !FormatFlags::UNDERLINE
-            };
+            }
+            // Only the overstrike is printed in this pass, and underline
+            // was already printed with the text
+            format.flags &= !FormatFlags::UNDERLINE;
             format
         },
         char_map: strikethrough_char_map,
@@ -515,15 +1137,7 @@ static PASSES: [LinePass; 4] = [
             };
             format
         },
-        char_map: |char, format, active| {
-            if active {
-                vec![char]
-            } else if format.control {
-                vec![]
-            } else {
-                vec![b' ']
-            }
-        },
+        char_map: text_char_map,
     },
     LinePass {
         name: "red strikethrough",
@@ -531,10 +1145,322 @@ static PASSES: [LinePass; 4] = [
         format_map: |mut format, active| {
             if !active {
                 format.red = true;
-                format.flags &= !FormatFlags::UNDERLINE
-            };
+            }
+            // Only the overstrike is printed in this pass, and underline
+            // was already printed with the text
+            format.flags &= !FormatFlags::UNDERLINE;
             format
         },
         char_map: strikethrough_char_map,
     },
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // One printed position in a pass: a character or a bit image, with
+    // the printer format it was printed in
+    #[derive(Debug)]
+    struct Cell {
+        char: Option<u8>,
+        image: Vec<u8>,
+        flags: FormatFlags,
+        red: bool,
+    }
+
+    // Split spooled output into lines of passes of cells
+    fn parse_passes(buf: &[u8]) -> Vec<Vec<Vec<Cell>>> {
+        let mut lines = vec![vec![]];
+        let mut pass = Vec::new();
+        let mut flags = FormatFlags::empty();
+        let mut red = false;
+        let mut i = 0;
+        while i < buf.len() {
+            match buf[i] {
+                0x1b => {
+                    match buf[i + 1] {
+                        b'!' => flags = FormatFlags::from_bits_truncate(buf[i + 2]),
+                        b'r' => red = buf[i + 2] != 0,
+                        b'3' | b'U' | b'a' | b'%' => {}
+                        b'*' => {
+                            let width = u16::from_le_bytes([buf[i + 3], buf[i + 4]]) as usize;
+                            pass.push(Cell {
+                                char: None,
+                                image: buf[i + 5..i + 5 + width].to_vec(),
+                                flags,
+                                red,
+                            });
+                            i += 2 + width;
+                        }
+                        other => panic!("unexpected command {:?}", other as char),
+                    }
+                    i += 3;
+                    continue;
+                }
+                b'\r' => lines.last_mut().unwrap().push(std::mem::take(&mut pass)),
+                b'\n' => lines.push(vec![]),
+                char => pass.push(Cell {
+                    char: Some(char),
+                    image: Vec::new(),
+                    flags,
+                    red,
+                }),
+            }
+            i += 1;
+        }
+        lines
+    }
+
+    #[test]
+    fn pass_combinations() {
+        let size = FormatFlags::NARROW | FormatFlags::DOUBLE_WIDTH;
+        for red in [false, true] {
+            for strikethrough in [false, true] {
+                for style in [FormatFlags::UNDERLINE, FormatFlags::EMPHASIZED] {
+                    for wide in [false, true] {
+                        let case = (red, strikethrough, style, wide);
+                        let mut format = Format::new()
+                            .with_red(red)
+                            .with_strikethrough(strikethrough)
+                            .with_flags(style);
+                        if wide {
+                            format = format
+                                .without_flags(FormatFlags::NARROW)
+                                .with_flags(FormatFlags::DOUBLE_WIDTH);
+                        }
+                        let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+                        renderer.buf.clear();
+                        renderer.write("a").unwrap();
+                        renderer.set_format(format.clone());
+                        renderer.write("x").unwrap();
+                        renderer.restore_format();
+                        renderer.write("b\n").unwrap();
+
+                        let lines = parse_passes(&renderer.buf);
+                        assert_eq!(lines.len(), 2, "{:?}", case);
+                        let passes = &lines[0];
+                        // black text, red text if any, and strikethrough
+                        assert_eq!(
+                            passes.len(),
+                            1 + red as usize + strikethrough as usize,
+                            "{:?}",
+                            case
+                        );
+                        let mut chars = Vec::new();
+                        let mut strikes = 0;
+                        for pass in passes {
+                            // every pass covers the same positions at the
+                            // same widths, so they line up
+                            assert_eq!(pass.len(), 3, "{:?}", case);
+                            for (i, cell) in pass.iter().enumerate() {
+                                let expected_size = if i == 1 {
+                                    format.flags
+                                } else {
+                                    Format::new().flags
+                                };
+                                assert_eq!(cell.flags & size, expected_size & size, "{:?}", case);
+                                match cell.char {
+                                    Some(b' ') => {
+                                        // padding is never underlined
+                                        assert!(
+                                            !cell.flags.contains(FormatFlags::UNDERLINE),
+                                            "{:?}",
+                                            case
+                                        );
+                                    }
+                                    Some(char) => chars.push((char, i, cell.red, cell.flags)),
+                                    None => {
+                                        assert_eq!(i, 1, "{:?}", case);
+                                        assert_eq!(cell.red, red, "{:?}", case);
+                                        assert_eq!(
+                                            cell.image.len(),
+                                            format.char_overstrike_width(&FontMetrics::default())
+                                        );
+                                        assert!(
+                                            cell.image.iter().all(|b| *b == 0x10),
+                                            "{:?}",
+                                            case
+                                        );
+                                        // underline doesn't apply to the strike
+                                        assert!(
+                                            !cell.flags.contains(FormatFlags::UNDERLINE),
+                                            "{:?}",
+                                            case
+                                        );
+                                        strikes += 1;
+                                    }
+                                }
+                            }
+                        }
+                        assert_eq!(strikes, strikethrough as usize, "{:?}", case);
+                        // each character printed once, in its own color
+                        // and style
+                        chars.sort_by_key(|c| c.1);
+                        assert_eq!(chars.len(), 3, "{:?}", case);
+                        assert_eq!((chars[0].0, chars[0].2), (b'a', false), "{:?}", case);
+                        assert_eq!((chars[1].0, chars[1].2), (b'x', red), "{:?}", case);
+                        assert_eq!((chars[2].0, chars[2].2), (b'b', false), "{:?}", case);
+                        assert!(chars[1].3.contains(style), "{:?}", case);
+                        for plain in [chars[0].3, chars[2].3] {
+                            assert!(
+                                !plain.intersects(FormatFlags::UNDERLINE | FormatFlags::EMPHASIZED),
+                                "{:?}",
+                                case
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn break_chars() {
+        // returns whether the slash ended up at the end of a line
+        let slash_at_eol = |break_chars| {
+            let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+            renderer.set_break_chars(break_chars).unwrap();
+            // 40 narrow characters fit on a line
+            renderer
+                .write(&format!("{}/{}\n", "a".repeat(30), "b".repeat(20)))
+                .unwrap();
+            assert_eq!(renderer.total_lines, 2);
+            renderer.buf.windows(2).any(|w| w == b"/\r")
+        };
+        assert!(!slash_at_eol(""));
+        assert!(slash_at_eol("-/"));
+        Renderer::new(Cursor::new(Vec::new()))
+            .set_break_chars("- ")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn left_margin() {
+        let count_lines = |margin| {
+            let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+            renderer.set_left_margin(margin).unwrap();
+            // narrow characters are 8 units wide, so 40 fit by default
+            renderer.write(&format!("{}\n", "a".repeat(35))).unwrap();
+            renderer.buf.iter().filter(|b| **b == b'\n').count()
+        };
+        assert_eq!(count_lines(0), 1);
+        assert_eq!(count_lines(80), 2);
+
+        let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+        renderer.set_left_margin(41).unwrap();
+        assert!(renderer.buf.ends_with(b"\x1dL\x29\x00"));
+        assert_eq!(renderer.line_pixels_text(), 279);
+        assert_eq!(renderer.line_pixels_image(), 179);
+        renderer.write_image(&StrikeImage::new(180, 8)).unwrap_err();
+        renderer.write_image(&StrikeImage::new(179, 8)).unwrap();
+
+        renderer.set_left_margin(161).unwrap_err();
+    }
+
+    #[test]
+    fn wrap_style() {
+        let lines = |marker, indent| {
+            let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+            renderer.set_wrap_style(marker, indent).unwrap();
+            renderer.buf.clear();
+            // 40 narrow characters fit on a line
+            renderer
+                .write(&format!(
+                    "{}\nshort\n{}\n",
+                    "abcd ".repeat(9).trim_end(),
+                    "x".repeat(45)
+                ))
+                .unwrap();
+            String::from_utf8(renderer.buf)
+                .unwrap()
+                .split("\r\n")
+                .map(|line| line.rsplit('\0').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lines(None, 0),
+            [
+                "abcd ".repeat(8).trim_end(),
+                "abcd",
+                "short",
+                &"x".repeat(40),
+                "xxxxx",
+                ""
+            ]
+        );
+        assert_eq!(
+            lines(Some('\\'), 2),
+            [
+                format!("{}\\", "abcd ".repeat(8).trim_end()),
+                "  abcd".into(),
+                "short".into(),
+                format!("{}\\", "x".repeat(39)),
+                "  xxxxxx".into(),
+                "".into(),
+            ]
+        );
+
+        let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+        renderer.set_wrap_style(Some('\u{21b5}'), 0).unwrap_err();
+        renderer.set_wrap_style(Some(' '), 0).unwrap_err();
+    }
+
+    #[test]
+    fn font_metrics() {
+        let metrics = FontMetrics {
+            narrow_width: 10,
+            narrow_overstrike: 6,
+            ..Default::default()
+        };
+        let mut renderer = Renderer::new(Cursor::new(Vec::new()));
+        assert_eq!(renderer.line_columns(), 40);
+        renderer.set_font_metrics(metrics).unwrap();
+        assert_eq!(renderer.line_columns(), 32);
+
+        // wrapping follows the character pitch
+        renderer.write(&format!("{}\n", "a".repeat(35))).unwrap();
+        assert_eq!(renderer.line_count(), 2);
+
+        // each struck character gets one overstrike segment of the
+        // configured width
+        renderer.buf.clear();
+        renderer.set_format(renderer.format().with_strikethrough(true));
+        renderer.write("ab\n").unwrap();
+        let segment = [b"\x1b*\x00\x06\x00".as_slice(), &[0x10; 6]].concat();
+        let count = renderer
+            .buf
+            .windows(segment.len())
+            .filter(|w| *w == segment.as_slice())
+            .count();
+        assert_eq!(count, 2);
+
+        renderer
+            .set_font_metrics(FontMetrics {
+                wide_width: 0,
+                ..Default::default()
+            })
+            .unwrap_err();
+        renderer
+            .set_font_metrics(FontMetrics {
+                wide_overstrike: 101,
+                ..Default::default()
+            })
+            .unwrap_err();
+        // 32 characters * 7 dots is wider than a bit image line
+        renderer
+            .set_font_metrics(FontMetrics {
+                narrow_width: 10,
+                narrow_overstrike: 7,
+                ..Default::default()
+            })
+            .unwrap_err();
+        renderer
+            .set_font_metrics(FontMetrics {
+                wide_width: 8,
+                ..Default::default()
+            })
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * Copyright 2023 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+use anyhow::{bail, Context, Result};
+use bitflags::bitflags;
+use std::io::{Read, Write};
+
+bitflags! {
+    /// Response to DLE EOT 2
+    pub struct OfflineStatus: u8 {
+        const COVER_OPEN = 0x04;
+        const FEED_BUTTON = 0x08;
+        const PAPER_END_STOP = 0x20;
+        const ERROR = 0x40;
+    }
+
+    /// Response to DLE EOT 3
+    pub struct ErrorStatus: u8 {
+        const MECHANICAL = 0x04;
+        const AUTOCUTTER = 0x08;
+        const UNRECOVERABLE = 0x20;
+        const AUTO_RECOVERABLE = 0x40;
+    }
+
+    /// Response to DLE EOT 4
+    pub struct PaperStatus: u8 {
+        const NEAR_END = 0x0c;
+        const END = 0x60;
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Status {
+    pub offline: OfflineStatus,
+    pub error: ErrorStatus,
+    pub paper: PaperStatus,
+}
+
+impl Status {
+    /// Query the printer's real-time status.  Any buffered output must
+    /// already have been sent.
+    pub fn read(device: &mut (impl Read + Write)) -> Result<Self> {
+        Ok(Self {
+            offline: OfflineStatus::from_bits_truncate(query(device, 2)?),
+            error: ErrorStatus::from_bits_truncate(query(device, 3)?),
+            paper: PaperStatus::from_bits_truncate(query(device, 4)?),
+        })
+    }
+
+    /// Descriptions of any conditions that prevent printing.
+    pub fn problems(&self) -> Vec<&'static str> {
+        let mut problems = Vec::new();
+        if self.offline.contains(OfflineStatus::COVER_OPEN) {
+            problems.push("cover open");
+        }
+        if self.out_of_paper() {
+            problems.push("out of paper");
+        }
+        if self.error.contains(ErrorStatus::MECHANICAL) {
+            problems.push("mechanical error, such as a paper jam");
+        }
+        if self.error.contains(ErrorStatus::AUTOCUTTER) {
+            problems.push("autocutter error");
+        }
+        if self.error.contains(ErrorStatus::UNRECOVERABLE) {
+            problems.push("unrecoverable error");
+        }
+        if self.error.contains(ErrorStatus::AUTO_RECOVERABLE) {
+            problems.push("print head overheated");
+        }
+        if problems.is_empty() && self.offline.contains(OfflineStatus::ERROR) {
+            problems.push("unknown error");
+        }
+        problems
+    }
+
+    /// Whether the printer has stopped because the paper ran out.
+    pub fn out_of_paper(&self) -> bool {
+        self.paper.contains(PaperStatus::END)
+            || self.offline.contains(OfflineStatus::PAPER_END_STOP)
+    }
+
+    /// Whether the paper near-end sensor has tripped.
+    pub fn low_paper(&self) -> bool {
+        self.paper.contains(PaperStatus::NEAR_END)
+    }
+
+    /// Fail if the printer reports any problems.
+    pub fn check(&self) -> Result<()> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            bail!("printer reported: {}", problems.join(", "));
+        }
+        Ok(())
+    }
+}
+
+fn query(device: &mut (impl Read + Write), n: u8) -> Result<u8> {
+    device
+        .write_all(&[0x10, 0x04, n])
+        .context("requesting printer status")?;
+    device.flush().context("requesting printer status")?;
+    let mut byte = [0u8];
+    device
+        .read_exact(&mut byte)
+        .context("reading printer status")?;
+    // bits 1 and 4 are always set; bits 0 and 7 are always clear
+    if byte[0] & 0x93 != 0x12 {
+        bail!("invalid printer status byte {:#04x}", byte[0]);
+    }
+    Ok(byte[0])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io;
+
+    /// A printer that answers reads from a canned list of responses
+    #[derive(Default)]
+    pub(crate) struct MockPrinter {
+        pub(crate) written: Vec<u8>,
+        pub(crate) responses: VecDeque<u8>,
+    }
+
+    impl Read for MockPrinter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (buf.first_mut(), self.responses.pop_front()) {
+                (Some(b), Some(response)) => {
+                    *b = response;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    impl Write for MockPrinter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_status() {
+        let mut printer = MockPrinter {
+            responses: [0x12, 0x12, 0x12].into(),
+            ..Default::default()
+        };
+        let status = Status::read(&mut printer).unwrap();
+        assert_eq!(printer.written, b"\x10\x04\x02\x10\x04\x03\x10\x04\x04");
+        assert!(status.problems().is_empty());
+        assert!(!status.low_paper());
+        status.check().unwrap();
+
+        let mut printer = MockPrinter {
+            responses: [0x12, 0x12, 0x1e].into(),
+            ..Default::default()
+        };
+        let status = Status::read(&mut printer).unwrap();
+        assert!(status.low_paper());
+        status.check().unwrap();
+
+        let mut printer = MockPrinter {
+            responses: [0x76, 0x1a, 0x7e].into(),
+            ..Default::default()
+        };
+        let status = Status::read(&mut printer).unwrap();
+        assert_eq!(
+            status.problems(),
+            ["cover open", "out of paper", "autocutter error"]
+        );
+        status.check().unwrap_err();
+
+        // bad fixed bits
+        let mut printer = MockPrinter {
+            responses: [0x00].into(),
+            ..Default::default()
+        };
+        Status::read(&mut printer).unwrap_err();
+        // no response
+        Status::read(&mut MockPrinter::default()).unwrap_err();
+    }
+}
@@ -25,25 +25,47 @@ pub struct StrikeColors {
 }
 
 impl StrikeColors {
-    pub fn new(bicolor: bool) -> Self {
-        let mut map = HashMap::from([
-            (Rgb([255, 255, 255]), Strike([0, 0])),
-            (Rgb([0, 0, 0]), Strike([1, 0])),
-        ]);
+    /// Map to white, `levels` evenly spaced shades of gray printed with
+    /// 1..=`levels` black strikes, and optionally red.
+    pub fn new(bicolor: bool, levels: u8) -> Self {
+        // Keep the palette in a fixed order, so colors equidistant from
+        // two palette entries always dither the same way
+        let mut palette: Vec<_> = (0..=levels)
+            .map(|strikes| {
+                let luma = 255 - (255 * strikes as u32 / levels.max(1) as u32) as u8;
+                (Rgb([luma, luma, luma]), Strike([strikes, 0]))
+            })
+            .collect();
         if bicolor {
-            map.insert(Rgb([255, 0, 0]), Strike([0, 1]));
+            palette.push((Rgb([255, 0, 0]), Strike([0, 1])));
         }
         Self {
-            colors: map.keys().cloned().collect(),
-            map,
+            colors: palette.iter().map(|(color, _)| *color).collect(),
+            map: palette.into_iter().collect(),
         }
     }
 
     pub fn map_image(&self, image: &RgbImage) -> StrikeImage {
-        let mut dithered = image.clone();
+        let (width, height) = image.dimensions();
+        let mut ret = StrikeImage::new(width, height);
+        if width == 0 || height == 0 {
+            return ret;
+        }
+        // dither() assumes at least two columns, so pad narrower images
+        // with white
+        let mut dithered = if width < 2 {
+            let mut padded = RgbImage::from_pixel(2, height, Rgb([255, 255, 255]));
+            for y in 0..height {
+                padded.put_pixel(0, y, *image.get_pixel(0, y));
+            }
+            padded
+        } else {
+            image.clone()
+        };
         dither(&mut dithered, self);
-        let mut ret = StrikeImage::new(image.width(), image.height());
-        for (orig, mapped) in zip(dithered.pixels(), ret.pixels_mut()) {
+        for (orig, mapped) in
+            zip(dithered.rows(), ret.rows_mut()).flat_map(|(orig, mapped)| zip(orig, mapped))
+        {
             *mapped = *self.map.get(orig).expect("unexpected pixel value");
         }
         ret
@@ -0,0 +1,520 @@
+/*
+ * Copyright 2026 Benjamin Gilbert
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+use anyhow::{bail, Result};
+use pulldown_cmark::Alignment;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use crate::render::{Format, FormatFlags, Renderer};
+
+/// Blank columns between table columns
+const COLUMN_GAP: usize = 2;
+
+/// A character of a cell, with the format it was written in.  Widths and
+/// padding are counted in characters rather than pixels: cells only hold
+/// inline formatting (emphasis, strong, strikethrough, code, links), none
+/// of which changes character width, and a table can't appear inside a
+/// heading, so every character of a table is as wide as the format it
+/// started in.
+type CellChar = (char, Rc<Format>);
+
+/// How to print a table whose columns don't fit in the line
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TableOverflow {
+    /// One row per line, with the cells separated by spaces
+    Flow,
+    /// Each row as a block of "header: value" lines
+    List,
+}
+
+/// Layout options for a table, set with a `table` code block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct TableOptions {
+    pub(crate) overflow: TableOverflow,
+    /// Treat the table as overflowing if fitting it would narrow a
+    /// column below this many characters
+    pub(crate) min_width: usize,
+    /// Shade every other data row
+    pub(crate) shade: bool,
+    /// Fixed width in characters for each column, or None for columns
+    /// sized to their contents
+    pub(crate) widths: Option<Vec<Option<usize>>>,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            overflow: TableOverflow::Flow,
+            min_width: 1,
+            shade: false,
+            widths: None,
+        }
+    }
+}
+
+/// A Markdown table, collected cell by cell and printed as aligned
+/// columns once it's complete.  Inline formatting in cells is kept;
+/// images print as their alt text.
+#[derive(Debug, Default)]
+pub(crate) struct Table {
+    alignments: Vec<Alignment>,
+    options: TableOptions,
+    header: Option<Vec<Vec<CellChar>>>,
+    rows: Vec<Vec<Vec<CellChar>>>,
+    row: Vec<Vec<CellChar>>,
+    cell: Option<Vec<CellChar>>,
+}
+
+impl Table {
+    pub(crate) fn new(alignments: Vec<Alignment>, options: TableOptions) -> Self {
+        Self {
+            alignments,
+            options,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn start_cell(&mut self) {
+        self.cell = Some(Vec::new());
+    }
+
+    /// Add text in the given format to the current cell, returning false
+    /// if we're not in one.
+    pub(crate) fn push_text(&mut self, text: &str, format: &Rc<Format>) -> bool {
+        match self.cell.as_mut() {
+            Some(cell) => {
+                cell.extend(text.chars().map(|c| (c, format.clone())));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn end_cell(&mut self) {
+        if let Some(mut cell) = self.cell.take() {
+            while cell.last().map_or(false, |(c, _)| c.is_whitespace()) {
+                cell.pop();
+            }
+            let start = cell
+                .iter()
+                .position(|(c, _)| !c.is_whitespace())
+                .unwrap_or(cell.len());
+            cell.drain(..start);
+            self.row.push(cell);
+        }
+    }
+
+    pub(crate) fn end_header(&mut self) {
+        self.header = Some(std::mem::take(&mut self.row));
+    }
+
+    pub(crate) fn end_row(&mut self) {
+        self.rows.push(std::mem::take(&mut self.row));
+    }
+
+    pub(crate) fn render(&self, renderer: &mut Renderer<impl Read + Write>) -> Result<()> {
+        self.check_width_hints(renderer.line_columns())?;
+        let widths = match self.column_widths(renderer.line_columns()) {
+            Some(widths) => widths,
+            None => {
+                return match self.options.overflow {
+                    TableOverflow::Flow => self.render_flowed(renderer),
+                    TableOverflow::List => self.render_list(renderer),
+                }
+            }
+        };
+        let format = renderer.format();
+        if let Some(header) = &self.header {
+            let header = restyle(header, |f| f.with_flags(FormatFlags::EMPHASIZED));
+            let emphasized = format.with_flags(FormatFlags::EMPHASIZED);
+            self.write_row(renderer, &header, &widths, &emphasized)?;
+            let rule: Vec<Vec<CellChar>> = widths
+                .iter()
+                .map(|width| vec![('-', format.clone()); *width])
+                .collect();
+            write_line(renderer, &join_columns(rule, &format))?;
+        }
+        for (row, pad) in self.styled_rows(&format) {
+            self.write_row(renderer, &row, &widths, &pad)?;
+        }
+        Ok(())
+    }
+
+    // Whether the data row with this index is shaded
+    fn shaded(&self, i: usize) -> bool {
+        self.options.shade && i % 2 == 1
+    }
+
+    // The data rows, with shading applied, and the format to pad each
+    // one with
+    fn styled_rows(&self, format: &Rc<Format>) -> Vec<(Vec<Vec<CellChar>>, Rc<Format>)> {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| match self.shaded(i) {
+                true => (
+                    restyle(row, |f| f.with_shade(true)),
+                    format.with_shade(true),
+                ),
+                false => (row.clone(), format.clone()),
+            })
+            .collect()
+    }
+
+    // Check that there's a width hint for each column, and that the
+    // fixed widths leave at least one character for each of the others
+    fn check_width_hints(&self, line_chars: usize) -> Result<()> {
+        let hints = match &self.options.widths {
+            Some(hints) => hints,
+            None => return Ok(()),
+        };
+        if hints.len() != self.alignments.len() {
+            bail!(
+                "table has {} columns but {} widths",
+                self.alignments.len(),
+                hints.len()
+            );
+        }
+        let gaps = COLUMN_GAP * hints.len().saturating_sub(1);
+        let needed = hints.iter().map(|hint| hint.unwrap_or(1)).sum::<usize>() + gaps;
+        if needed > line_chars {
+            bail!(
+                "table widths need {} characters, more than the {}-character line",
+                needed,
+                line_chars
+            );
+        }
+        Ok(())
+    }
+
+    // Size each column to its width hint, or else its widest cell.  If
+    // the table is wider than the line, narrow the widest column without
+    // a hint one character at a time, preferring the rightmost on ties,
+    // until it fits.  Returns None if that would make a column narrower
+    // than the minimum width, or its contents if they're narrower.
+    fn column_widths(&self, line_chars: usize) -> Option<Vec<usize>> {
+        let mut widths = vec![1; self.alignments.len()];
+        for row in self.header.iter().chain(self.rows.iter()) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let mut minimums: Vec<usize> = widths
+            .iter()
+            .map(|width| (*width).min(self.options.min_width))
+            .collect();
+        for (i, hint) in self.options.widths.iter().flatten().enumerate() {
+            if let Some(hint) = hint {
+                widths[i] = *hint;
+                minimums[i] = *hint;
+            }
+        }
+        let gaps = COLUMN_GAP * widths.len().saturating_sub(1);
+        if minimums.iter().sum::<usize>() + gaps > line_chars {
+            return None;
+        }
+        while widths.iter().sum::<usize>() + gaps > line_chars {
+            let (widest, _) = widths
+                .iter_mut()
+                .zip(&minimums)
+                .filter(|(width, minimum)| **width > **minimum)
+                .max_by_key(|(width, _)| **width)
+                .expect("table can be narrowed");
+            *widest -= 1;
+        }
+        Some(widths)
+    }
+
+    // Print each row's cells on one line, separated by spaces and
+    // wrapping like ordinary text, for tables with too many columns to
+    // align
+    fn render_flowed(&self, renderer: &mut Renderer<impl Read + Write>) -> Result<()> {
+        let format = renderer.format();
+        if let Some(header) = &self.header {
+            let emphasized = format.with_flags(FormatFlags::EMPHASIZED);
+            let header = restyle(header, |f| f.with_flags(FormatFlags::EMPHASIZED));
+            write_line(renderer, &join_columns(header, &emphasized))?;
+        }
+        for (row, pad) in self.styled_rows(&format) {
+            write_line(renderer, &join_columns(row, &pad))?;
+        }
+        Ok(())
+    }
+
+    // Print each row as a block of lines labeled with the column
+    // headers, for tables too wide to print as columns
+    fn render_list(&self, renderer: &mut Renderer<impl Read + Write>) -> Result<()> {
+        let format = renderer.format();
+        let labels = self
+            .header
+            .as_ref()
+            .map(|header| restyle(header, |f| f.with_flags(FormatFlags::EMPHASIZED)));
+        for (i, (row, pad)) in self.styled_rows(&format).into_iter().enumerate() {
+            if i > 0 {
+                renderer.write("\n")?;
+            }
+            for (j, cell) in row.into_iter().enumerate() {
+                let mut line = labels
+                    .as_ref()
+                    .and_then(|labels| labels.get(j))
+                    .cloned()
+                    .unwrap_or_default();
+                if self.shaded(i) {
+                    line = restyle(&[line], |f| f.with_shade(true)).remove(0);
+                }
+                if !line.is_empty() {
+                    line.extend([(':', line[line.len() - 1].1.clone()), (' ', pad.clone())]);
+                }
+                line.extend(cell);
+                write_line(renderer, &line)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write one table row, wrapping cells that don't fit in their
+    // column onto additional lines.  Padding is printed in `format`.
+    fn write_row(
+        &self,
+        renderer: &mut Renderer<impl Read + Write>,
+        row: &[Vec<CellChar>],
+        widths: &[usize],
+        format: &Rc<Format>,
+    ) -> Result<()> {
+        let cells: Vec<Vec<Vec<CellChar>>> = widths
+            .iter()
+            .enumerate()
+            .map(|(i, width)| wrap_cell(row.get(i).map(Vec::as_slice).unwrap_or(&[]), *width))
+            .collect();
+        let height = cells.iter().map(Vec::len).max().unwrap_or(0);
+        for line in 0..height {
+            let columns: Vec<Vec<CellChar>> = cells
+                .iter()
+                .zip(widths)
+                .zip(&self.alignments)
+                .map(|((cell, width), alignment)| {
+                    align(
+                        cell.get(line).map(Vec::as_slice).unwrap_or(&[]),
+                        *width,
+                        *alignment,
+                        format,
+                    )
+                })
+                .collect();
+            write_line(renderer, &join_columns(columns, format))?;
+        }
+        Ok(())
+    }
+}
+
+// Change the format of every character of each cell
+fn restyle(row: &[Vec<CellChar>], change: impl Fn(&Format) -> Rc<Format>) -> Vec<Vec<CellChar>> {
+    row.iter()
+        .map(|cell| {
+            cell.iter()
+                .map(|(c, format)| (*c, change(format)))
+                .collect()
+        })
+        .collect()
+}
+
+// Join padded columns into a line, with gaps in `format`
+fn join_columns(columns: Vec<Vec<CellChar>>, format: &Rc<Format>) -> Vec<CellChar> {
+    let mut line = Vec::new();
+    for (i, column) in columns.into_iter().enumerate() {
+        if i > 0 {
+            line.extend(std::iter::repeat((' ', format.clone())).take(COLUMN_GAP));
+        }
+        line.extend(column);
+    }
+    line
+}
+
+// Write a line, switching formats between runs of characters.  Trailing
+// spaces are dropped so they don't carry over into the next line.
+fn write_line(renderer: &mut Renderer<impl Read + Write>, line: &[CellChar]) -> Result<()> {
+    let end = line
+        .iter()
+        .rposition(|(c, _)| *c != ' ')
+        .map_or(0, |i| i + 1);
+    let mut start = 0;
+    while start < end {
+        let format = &line[start].1;
+        let run = line[start..end]
+            .iter()
+            .position(|(_, f)| **f != **format)
+            .map_or(end, |len| start + len);
+        let text: String = line[start..run].iter().map(|(c, _)| c).collect();
+        renderer.set_format(format.clone());
+        let result = renderer.write(&text);
+        renderer.restore_format();
+        result?;
+        start = run;
+    }
+    renderer.write("\n")
+}
+
+fn align(
+    cell: &[CellChar],
+    width: usize,
+    alignment: Alignment,
+    format: &Rc<Format>,
+) -> Vec<CellChar> {
+    let padding = width.saturating_sub(cell.len());
+    let left = match alignment {
+        Alignment::None | Alignment::Left => 0,
+        Alignment::Center => padding / 2,
+        Alignment::Right => padding,
+    };
+    let pad = |count| std::iter::repeat((' ', format.clone())).take(count);
+    pad(left)
+        .chain(cell.iter().cloned())
+        .chain(pad(padding - left))
+        .collect()
+}
+
+// Wrap a cell at spaces into lines of at most `width` characters,
+// splitting words that are longer than a line.
+fn wrap_cell(cell: &[CellChar], width: usize) -> Vec<Vec<CellChar>> {
+    let mut lines = Vec::new();
+    let mut line: Vec<CellChar> = Vec::new();
+    for word in cell.split(|(c, _)| c.is_whitespace()) {
+        if word.is_empty() {
+            continue;
+        }
+        let mut word = word.to_vec();
+        if !line.is_empty() && line.len() + 1 + word.len() <= width {
+            // the space takes the format of the character before it
+            let format = line[line.len() - 1].1.clone();
+            line.push((' ', format));
+        } else if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        while word.len() > width {
+            lines.push(word.drain(..width).collect());
+        }
+        line.extend(word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Vec<CellChar> {
+        let format = Format::new();
+        text.chars().map(|c| (c, format.clone())).collect()
+    }
+
+    fn text(chars: &[CellChar]) -> String {
+        chars.iter().map(|(c, _)| c).collect()
+    }
+
+    #[test]
+    fn wrap() {
+        let wrap = |cell: &str, width| -> Vec<String> {
+            wrap_cell(&chars(cell), width)
+                .iter()
+                .map(|line| text(line))
+                .collect()
+        };
+        assert_eq!(wrap("", 5), [""]);
+        assert_eq!(wrap("two words", 9), ["two words"]);
+        assert_eq!(wrap("two  words", 8), ["two", "words"]);
+        assert_eq!(wrap("a b c d", 3), ["a b", "c d"]);
+        assert_eq!(
+            wrap("see https://example.com/x", 6),
+            ["see", "https:", "//exam", "ple.co", "m/x"]
+        );
+        assert_eq!(wrap("abcdef", 3), ["abc", "def"]);
+
+        // formats follow their characters
+        let bold = Format::new().with_flags(FormatFlags::EMPHASIZED);
+        let mut cell = chars("a ");
+        cell.extend("bc".chars().map(|c| (c, bold.clone())));
+        let lines = wrap_cell(&cell, 2);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].iter().all(|(_, format)| *format == bold));
+    }
+
+    #[test]
+    fn alignment() {
+        let pad = Format::new();
+        let align =
+            |cell: &str, width, alignment| text(&align(&chars(cell), width, alignment, &pad));
+        assert_eq!(align("ab", 5, Alignment::None), "ab   ");
+        assert_eq!(align("ab", 5, Alignment::Left), "ab   ");
+        assert_eq!(align("ab", 5, Alignment::Center), " ab  ");
+        assert_eq!(align("ab", 5, Alignment::Right), "   ab");
+        assert_eq!(align("abc", 3, Alignment::Right), "abc");
+    }
+
+    #[test]
+    fn widths() {
+        let format = Format::new();
+        let mut table = Table::new(vec![Alignment::None; 3], Default::default());
+        for row in [["Qty", "Item", "Price"], ["2", "Burger", "$12.00"]] {
+            for cell in row {
+                table.start_cell();
+                table.push_text(cell, &format);
+                table.end_cell();
+            }
+            table.end_row();
+        }
+        assert_eq!(table.column_widths(40).unwrap(), [3, 6, 6]);
+        // exactly fits
+        assert_eq!(table.column_widths(19).unwrap(), [3, 6, 6]);
+        // rightmost of the widest shrinks first
+        assert_eq!(table.column_widths(18).unwrap(), [3, 6, 5]);
+        assert_eq!(table.column_widths(17).unwrap(), [3, 5, 5]);
+        assert_eq!(table.column_widths(7).unwrap(), [1, 1, 1]);
+        assert_eq!(table.column_widths(6), None);
+
+        // columns aren't narrowed below the minimum width, unless their
+        // contents are narrower
+        table.options.min_width = 5;
+        assert_eq!(table.column_widths(18).unwrap(), [3, 6, 5]);
+        assert_eq!(table.column_widths(17).unwrap(), [3, 5, 5]);
+        assert_eq!(table.column_widths(16), None);
+
+        // hinted columns keep their width, and the others are narrowed
+        table.options.min_width = 1;
+        table.options.widths = Some(vec![Some(8), None, None]);
+        assert_eq!(table.column_widths(40).unwrap(), [8, 6, 6]);
+        assert_eq!(table.column_widths(20).unwrap(), [8, 4, 4]);
+        table.check_width_hints(14).unwrap();
+        assert_eq!(table.column_widths(14).unwrap(), [8, 1, 1]);
+        table.check_width_hints(13).unwrap_err();
+        table.options.widths = Some(vec![Some(8), None]);
+        table.check_width_hints(40).unwrap_err();
+
+        // missing cells
+        let mut table = Table::new(vec![Alignment::None; 2], Default::default());
+        assert!(!table.push_text("outside", &format));
+        table.start_cell();
+        assert!(table.push_text(" x ", &format));
+        table.end_cell();
+        table.end_row();
+        assert_eq!(table.rows.len(), 1);
+        assert_eq!(text(&table.rows[0][0]), "x");
+        assert_eq!(table.column_widths(40).unwrap(), [1, 1]);
+    }
+}